@@ -0,0 +1,198 @@
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+#[cfg(unix)]
+use tklog::async_info;
+
+use std::io;
+
+use crate::Forward;
+use crate::resolver::Resolver;
+use crate::stream::BoxStream;
+
+// 转发地址：host:port 表示 TCP，unix:/path 表示 Unix 域套接字
+const UNIX_PREFIX: &str = "unix:";
+
+// unix: 地址的套接字路径，TCP 地址返回 None
+pub fn unix_path(addr: &str) -> Option<&str> {
+    addr.strip_prefix(UNIX_PREFIX)
+}
+
+// TCP 地址通过 resolver 解析，按 Happy Eyeballs 连接
+pub async fn connect(addr: &str, resolver: &Resolver) -> io::Result<BoxStream> {
+    match unix_path(addr) {
+        Some(path) => connect_unix(path).await,
+        None => Ok(Box::new(resolver.connect(addr).await?)),
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &str) -> io::Result<BoxStream> {
+    Ok(Box::new(UnixStream::connect(path).await?))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_path: &str) -> io::Result<BoxStream> {
+    Err(unsupported())
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    // 绑定监听地址，Unix 域套接字按 unix_mode / unix_owner 设置权限和属主
+    pub async fn bind(addr: &str, forward: &Forward) -> io::Result<Listener> {
+        match unix_path(addr) {
+            Some(path) => bind_unix(path, forward).await,
+            None => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+        }
+    }
+
+    // 接受一个连接，返回字节流和日志中使用的对端描述
+    pub async fn accept(&self) -> io::Result<(BoxStream, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, addr) = listener.accept().await?;
+                Ok((Box::new(socket), addr.ip().to_string()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (socket, _) = listener.accept().await?;
+                // Unix 域套接字的对端没有地址，用对端进程的 uid 和 pid 标识
+                let peer = match socket.peer_cred() {
+                    Ok(cred) => match cred.pid() {
+                        Some(pid) => format!("unix uid {} pid {}", cred.uid(), pid),
+                        None => format!("unix uid {}", cred.uid()),
+                    },
+                    Err(_) => "unix".to_string(),
+                };
+                Ok((Box::new(socket), peer))
+            }
+        }
+    }
+}
+
+// 检查 unix: 地址和 unix_mode / unix_owner 配置，启动前发现错误
+#[cfg(unix)]
+pub fn validate_unix_options(forward: &Forward) -> Result<(), String> {
+    if let Some(mode) = &forward.unix_mode {
+        parse_mode(mode)?;
+    }
+    if let Some(owner) = &forward.unix_owner {
+        resolve_owner(owner)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn validate_unix_options(forward: &Forward) -> Result<(), String> {
+    let uses_unix = unix_path(&forward.local_addr).is_some() || unix_path(&forward.remote_addr).is_some();
+    if uses_unix || forward.unix_mode.is_some() || forward.unix_owner.is_some() {
+        return Err(unsupported().to_string());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "Unix domain sockets are not supported on this platform")
+}
+
+#[cfg(not(unix))]
+async fn bind_unix(_path: &str, _forward: &Forward) -> io::Result<Listener> {
+    Err(unsupported())
+}
+
+#[cfg(unix)]
+async fn bind_unix(path: &str, forward: &Forward) -> io::Result<Listener> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let with_path = |e: io::Error| io::Error::new(e.kind(), format!("[ {} ] {}: {}", forward.name, path, e));
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, format!("[ {} ] {}", forward.name, msg));
+
+    remove_stale_socket(path, forward).await.map_err(with_path)?;
+    let listener = UnixListener::bind(path).map_err(with_path)?;
+
+    if let Some(mode) = &forward.unix_mode {
+        let mode = parse_mode(mode).map_err(invalid)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).map_err(with_path)?;
+    }
+    if let Some(owner) = &forward.unix_owner {
+        let (uid, gid) = resolve_owner(owner).map_err(invalid)?;
+        std::os::unix::fs::chown(path, uid, gid).map_err(with_path)?;
+    }
+    Ok(Listener::Unix(listener))
+}
+
+// 启动时清理上次异常退出留下的套接字文件：只删除没有进程在监听的套接字
+#[cfg(unix)]
+async fn remove_stale_socket(path: &str, forward: &Forward) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "file exists and is not a socket"));
+    }
+
+    match UnixStream::connect(path).await {
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, "socket is in use by another process")),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            std::fs::remove_file(path)?;
+            async_info!("[ ",forward.name," ] Removed stale socket file ",path);
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// 八进制权限，例如 "660" 或 "0660"
+#[cfg(unix)]
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("unix_mode {} is not an octal file mode", mode))
+}
+
+// 属主，"user"、"user:group" 或 ":group"，也可以使用数字 ID
+#[cfg(unix)]
+fn resolve_owner(owner: &str) -> Result<(Option<u32>, Option<u32>), String> {
+    use nix::unistd::{Group, User};
+
+    let (user, group) = match owner.split_once(':') {
+        Some((user, group)) => (user, group),
+        None => (owner, ""),
+    };
+
+    let uid = match user {
+        "" => None,
+        user => Some(match user.parse::<u32>() {
+            Ok(uid) => uid,
+            Err(_) => User::from_name(user)
+                .map_err(|e| format!("unix_owner: look up user {}: {}", user, e))?
+                .ok_or_else(|| format!("unix_owner: user {} does not exist", user))?
+                .uid
+                .as_raw(),
+        }),
+    };
+    let gid = match group {
+        "" => None,
+        group => Some(match group.parse::<u32>() {
+            Ok(gid) => gid,
+            Err(_) => Group::from_name(group)
+                .map_err(|e| format!("unix_owner: look up group {}: {}", group, e))?
+                .ok_or_else(|| format!("unix_owner: group {} does not exist", group))?
+                .gid
+                .as_raw(),
+        }),
+    };
+    Ok((uid, gid))
+}
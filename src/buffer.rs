@@ -2,7 +2,9 @@ use std::collections::VecDeque;
 
 
 
-use crate::encryption::SimpleEncryptionContext;
+use crate::encryption::{FRAME_HEADER_LEN, SimpleEncryptionContext};
+use crate::frame::Frame;
+use crate::padding::unpad;
 use std::io;
 
 // 最大数据包大小
@@ -23,34 +25,37 @@ impl PacketBuffer {
         self.buffer.extend(data);
     }
 
-    // 尝试从缓冲区读取一个完整的数据包
-    pub fn try_read_packet(&mut self, ctx: &SimpleEncryptionContext) -> io::Result<Option<Vec<u8>>> {
-        if self.buffer.len() < 4 {
+    // 尝试从缓冲区读取一个完整的数据包，解密、去掉填充后按帧类型解析
+    pub fn try_read_packet(&mut self, ctx: &mut SimpleEncryptionContext) -> io::Result<Option<Frame>> {
+        if self.buffer.len() < FRAME_HEADER_LEN {
             return Ok(None);
         }
 
-        // 查看长度前缀（但不移除）
-        let len_bytes: Vec<u8> = self.buffer.range(0..4).copied().collect();
-        let packet_len = u32::from_be_bytes([len_bytes[0], len_bytes[1], len_bytes[2], len_bytes[3]]) as usize;
+        // 查看帧头（但不移除）
+        let header: Vec<u8> = self.buffer.range(0..FRAME_HEADER_LEN).copied().collect();
+        let packet_len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let key_id = header[4];
+        let mut sequence_bytes = [0u8; 8];
+        sequence_bytes.copy_from_slice(&header[5..FRAME_HEADER_LEN]);
+        let sequence = u64::from_be_bytes(sequence_bytes);
 
         if packet_len > MAX_PACKET_SIZE {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "Packet too large"));
         }
 
-        if self.buffer.len() < 4 + packet_len {
+        if self.buffer.len() < FRAME_HEADER_LEN + packet_len {
             return Ok(None);
         }
 
-        // 移除长度前缀
-        for _ in 0..4 {
-            self.buffer.pop_front();
-        }
+        // 移除帧头
+        self.buffer.drain(0..FRAME_HEADER_LEN);
 
         // 提取加密数据
         let encrypted_data: Vec<u8> = self.buffer.drain(0..packet_len).collect();
-        let decrypted_data = ctx.decrypt(&encrypted_data)?;
+        let decrypted_data = ctx.decrypt(key_id, sequence, &encrypted_data)?;
 
-        Ok(Some(decrypted_data))
+        // 去掉发送方的填充
+        Ok(Some(Frame::decode(unpad(decrypted_data)?)?))
 
     }
 
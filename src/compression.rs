@@ -0,0 +1,142 @@
+use serde::Deserialize;
+use std::io;
+
+// 解压后单帧的大小上限，防止异常数据占用过多内存
+const MAX_DECOMPRESSED_SIZE: usize = 65536;
+const ZSTD_LEVEL: i32 = 3;
+
+// 压缩帧的首字节标记：压缩后没有变小的帧按原样发送
+const FRAME_RAW: u8 = 0;
+const FRAME_COMPRESSED: u8 = 1;
+
+// 压缩标记占用的字节数，压缩后的帧最多比原数据长这么多
+pub const COMPRESSION_OVERHEAD: usize = 1;
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "zstd")]
+    Zstd,
+    #[serde(rename = "lz4")]
+    Lz4,
+}
+
+impl Compression {
+    // 握手中使用的编号
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Compression> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Zstd),
+            2 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+        }
+    }
+
+    // 两端配置一致时启用压缩，否则不压缩
+    pub fn agree(self, peer: Compression) -> Compression {
+        if self == peer { self } else { Compression::None }
+    }
+}
+
+// 单个方向的压缩器，同时统计压缩前后的字节数
+pub struct Compressor {
+    compression: Compression,
+    raw_bytes: u64,
+    wire_bytes: u64,
+}
+
+impl Compressor {
+    pub fn new(compression: Compression) -> Self {
+        Self { compression, raw_bytes: 0, wire_bytes: 0 }
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    // 在加密前压缩一帧数据
+    pub fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        if self.compression == Compression::None {
+            return Ok(data.to_vec());
+        }
+
+        let compressed = match self.compression {
+            Compression::None => unreachable!(),
+            Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL)?,
+            Compression::Lz4 => lz4_flex::block::compress_prepend_size(data),
+        };
+
+        let mut frame = Vec::with_capacity(1 + compressed.len().min(data.len()));
+        if compressed.len() < data.len() {
+            frame.push(FRAME_COMPRESSED);
+            frame.extend_from_slice(&compressed);
+        } else {
+            frame.push(FRAME_RAW);
+            frame.extend_from_slice(data);
+        }
+
+        self.raw_bytes += data.len() as u64;
+        self.wire_bytes += frame.len() as u64;
+        Ok(frame)
+    }
+
+    // 在解密后还原一帧数据
+    pub fn decompress(&mut self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        if self.compression == Compression::None {
+            return Ok(frame.to_vec());
+        }
+
+        let (&marker, payload) = frame.split_first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Empty compressed frame"))?;
+        let data = match (marker, self.compression) {
+            (FRAME_RAW, _) => payload.to_vec(),
+            (FRAME_COMPRESSED, Compression::Zstd) => zstd::bulk::decompress(payload, MAX_DECOMPRESSED_SIZE)?,
+            (FRAME_COMPRESSED, Compression::Lz4) => {
+                let (size, _) = lz4_flex::block::uncompressed_size(payload).map_err(invalid_data)?;
+                if size > MAX_DECOMPRESSED_SIZE {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Decompressed frame too large"));
+                }
+                lz4_flex::block::decompress_size_prepended(payload).map_err(invalid_data)?
+            }
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown compressed frame marker {}", marker)));
+            }
+        };
+
+        self.raw_bytes += data.len() as u64;
+        self.wire_bytes += frame.len() as u64;
+        Ok(data)
+    }
+
+    // 统计摘要，例如 "1048576 -> 262144 bytes (25.0%)"
+    pub fn summary(&self) -> String {
+        let ratio = if self.raw_bytes == 0 {
+            100.0
+        } else {
+            self.wire_bytes as f64 * 100.0 / self.raw_bytes as f64
+        };
+        format!("{} -> {} bytes ({:.1}%)", self.raw_bytes, self.wire_bytes, ratio)
+    }
+}
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Decompression failed: {}", e))
+}
@@ -1,62 +1,391 @@
 use aes_gcm::{
-    aead::{Aead, KeyInit, generic_array::GenericArray},
+    aead::{Aead, KeyInit, OsRng, Payload, generic_array::GenericArray, rand_core::RngCore},
     Aes256Gcm
 };
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+use argon2::{Algorithm, Argon2, Params, Version};
 
-use aes_gcm::aead::generic_array::typenum::U12;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
 use std::io;
-// 固定密钥和固定 nonce
-const FIXED_KEY: [u8; 32] = [
-    0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
-    0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f, 0x10,
-    0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18,
-    0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f, 0x20,
-];
-
-const FIXED_NONCE: [u8; 12] = [
-    0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c,
-];
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
+
+use crate::padding::Padding;
+
+// 密钥长度（三种加密套件均为 256 位密钥）
+pub const KEY_LEN: usize = 32;
+
+pub type Key = [u8; KEY_LEN];
+
+// 每个方向的 nonce 由握手派生的盐与帧序号异或得到，按最长的 XChaCha20 nonce 派生
+pub const NONCE_SALT_LEN: usize = 24;
+
+// 加密套件，握手时校验两端一致
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub enum CipherSuite {
+    #[default]
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+    #[serde(rename = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+}
+
+impl CipherSuite {
+    pub fn id(self) -> u8 {
+        match self {
+            CipherSuite::Aes256Gcm => 1,
+            CipherSuite::ChaCha20Poly1305 => 2,
+            CipherSuite::XChaCha20Poly1305 => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(CipherSuite::Aes256Gcm),
+            2 => Some(CipherSuite::ChaCha20Poly1305),
+            3 => Some(CipherSuite::XChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CipherSuite::Aes256Gcm => "aes-256-gcm",
+            CipherSuite::ChaCha20Poly1305 => "chacha20-poly1305",
+            CipherSuite::XChaCha20Poly1305 => "xchacha20-poly1305",
+        }
+    }
+
+    fn nonce_len(self) -> usize {
+        match self {
+            CipherSuite::Aes256Gcm | CipherSuite::ChaCha20Poly1305 => 12,
+            CipherSuite::XChaCha20Poly1305 => 24,
+        }
+    }
+}
+
+enum AeadCipher {
+    // AES 轮密钥较大，装箱避免枚举体积膨胀
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+    XChaCha20Poly1305(XChaCha20Poly1305),
+}
+
+impl AeadCipher {
+    fn new(suite: CipherSuite, key: &Key) -> Self {
+        let key = GenericArray::from_slice(key);
+        match suite {
+            CipherSuite::Aes256Gcm => AeadCipher::Aes256Gcm(Box::new(Aes256Gcm::new(key))),
+            CipherSuite::ChaCha20Poly1305 => AeadCipher::ChaCha20Poly1305(ChaCha20Poly1305::new(key)),
+            CipherSuite::XChaCha20Poly1305 => AeadCipher::XChaCha20Poly1305(XChaCha20Poly1305::new(key)),
+        }
+    }
+
+    fn encrypt(&self, nonce: &[u8], payload: Payload) -> Result<Vec<u8>, aes_gcm::aead::Error> {
+        match self {
+            AeadCipher::Aes256Gcm(c) => c.encrypt(GenericArray::from_slice(nonce), payload),
+            AeadCipher::ChaCha20Poly1305(c) => c.encrypt(GenericArray::from_slice(nonce), payload),
+            AeadCipher::XChaCha20Poly1305(c) => c.encrypt(GenericArray::from_slice(nonce), payload),
+        }
+    }
+
+    fn decrypt(&self, nonce: &[u8], payload: Payload) -> Result<Vec<u8>, aes_gcm::aead::Error> {
+        match self {
+            AeadCipher::Aes256Gcm(c) => c.decrypt(GenericArray::from_slice(nonce), payload),
+            AeadCipher::ChaCha20Poly1305(c) => c.decrypt(GenericArray::from_slice(nonce), payload),
+            AeadCipher::XChaCha20Poly1305(c) => c.decrypt(GenericArray::from_slice(nonce), payload),
+        }
+    }
+}
+
+// 帧头：4 字节密文长度 + 1 字节密钥 ID + 8 字节帧序号
+pub const FRAME_HEADER_LEN: usize = 4 + 1 + 8;
+
+// 帧方向，与密钥 ID、帧序号一起作为附加认证数据，防止帧被反射到另一个方向
+#[derive(Clone, Copy)]
+pub enum Direction {
+    InitiatorToResponder = 0,
+    ResponderToInitiator = 1,
+}
+
+// 一侧配置的密钥集合：按 ID 接受多个密钥，active 为发送时使用的密钥
+pub struct KeyRing {
+    keys: BTreeMap<u8, Key>,
+    active: u8,
+}
+
+// 密钥集合可在运行中重新加载，已建立的会话在下一帧即使用新的密钥集合
+pub type SharedKeyRing = Arc<RwLock<KeyRing>>;
+
+impl KeyRing {
+    pub fn new(keys: BTreeMap<u8, Key>, active: u8) -> io::Result<Self> {
+        if !keys.contains_key(&active) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Active key id {} is not in the configured keys", active),
+            ));
+        }
+        Ok(Self { keys, active })
+    }
+
+    pub fn active(&self) -> (u8, Key) {
+        (self.active, self.keys[&self.active])
+    }
+
+    pub fn get(&self, id: u8) -> Option<Key> {
+        self.keys.get(&id).copied()
+    }
+
+    // 用于日志和错误信息
+    pub fn ids(&self) -> String {
+        self.keys.keys().map(|id| id.to_string()).collect::<Vec<_>>().join(", ")
+    }
+}
+
+pub fn read_key_ring(ring: &SharedKeyRing) -> RwLockReadGuard<'_, KeyRing> {
+    ring.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+// 会话的帧密钥派生，由握手实现：同一会话可按密钥 ID 派生出不同的帧密钥
+pub trait KeySchedule: Send + Sync {
+    fn frame_key(&self, psk: &Key, direction: Direction) -> (Key, [u8; NONCE_SALT_LEN]);
+}
+
+struct FrameCipher {
+    psk: Key,
+    cipher: AeadCipher,
+    salt: [u8; NONCE_SALT_LEN],
+}
 
 pub struct SimpleEncryptionContext {
-    cipher: Aes256Gcm,
-    nonce: GenericArray<u8, U12>,
+    suite: CipherSuite,
+    direction: Direction,
+    schedule: Arc<dyn KeySchedule>,
+    keyring: SharedKeyRing,
+    // 已派生的帧密钥，按密钥 ID 缓存
+    ciphers: HashMap<u8, FrameCipher>,
+    // 发送方向为下一个要发送的序号，接收方向为下一个期望的序号；切换密钥时序号继续递增
+    sequence: u64,
+    // 发送方向的填充策略
+    padding: Padding,
 }
 
 impl SimpleEncryptionContext {
-    pub fn new() -> Self {
-        let key = GenericArray::from_slice(&FIXED_KEY);
-        let cipher = Aes256Gcm::new(key);
-        let nonce = GenericArray::clone_from_slice(&FIXED_NONCE);
-        Self { cipher, nonce }
+    pub fn new(suite: CipherSuite, direction: Direction, schedule: Arc<dyn KeySchedule>, keyring: SharedKeyRing) -> Self {
+        Self { suite, direction, schedule, keyring, ciphers: HashMap::new(), sequence: 0, padding: Padding::None }
     }
 
-    pub fn encrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
-        self.cipher.encrypt(&self.nonce, data)
-            .map_err(|_e| io::Error::new(io::ErrorKind::Other, "Encryption failed"))
+    pub fn padding(&self) -> &Padding {
+        &self.padding
     }
 
-    pub fn decrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
-        self.cipher.decrypt(&self.nonce, data)
-            .map_err(|_e| io::Error::new(io::ErrorKind::Other, "Decryption failed"))
+    pub fn set_padding(&mut self, padding: Padding) {
+        self.padding = padding;
+    }
+
+    // 按密钥 ID 取帧密钥，密钥已从密钥集合移除时拒绝，密钥内容变化时重新派生
+    fn frame_cipher(&mut self, key_id: u8) -> io::Result<&FrameCipher> {
+        let psk = {
+            let ring = read_key_ring(&self.keyring);
+            ring.get(key_id).ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown key id {}, accepted key ids: {}", key_id, ring.ids()),
+            ))?
+        };
+
+        if self.ciphers.get(&key_id).is_none_or(|cached| cached.psk != psk) {
+            let (key, salt) = self.schedule.frame_key(&psk, self.direction);
+            let cipher = AeadCipher::new(self.suite, &key);
+            self.ciphers.insert(key_id, FrameCipher { psk, cipher, salt });
+        }
+        Ok(&self.ciphers[&key_id])
+    }
+
+    fn nonce(suite: CipherSuite, salt: &[u8; NONCE_SALT_LEN], sequence: u64) -> Vec<u8> {
+        let nonce_len = suite.nonce_len();
+        let mut nonce = salt[..nonce_len].to_vec();
+        for (n, c) in nonce[nonce_len - 8..].iter_mut().zip(sequence.to_be_bytes()) {
+            *n ^= c;
+        }
+        nonce
+    }
+
+    fn associated_data(direction: Direction, key_id: u8, sequence: u64) -> [u8; 10] {
+        let mut aad = [0u8; 10];
+        aad[0] = direction as u8;
+        aad[1] = key_id;
+        aad[2..].copy_from_slice(&sequence.to_be_bytes());
+        aad
+    }
+
+    // 用当前生效的密钥加密下一帧，返回密钥 ID、帧序号和密文；序号用尽时返回错误以断开连接，绝不重复使用 nonce
+    pub fn encrypt(&mut self, data: &[u8]) -> io::Result<(u8, u64, Vec<u8>)> {
+        let sequence = self.sequence;
+        if sequence == u64::MAX {
+            return Err(io::Error::other("Frame sequence exhausted, closing connection"));
+        }
+
+        let (key_id, _) = read_key_ring(&self.keyring).active();
+        let (suite, direction) = (self.suite, self.direction);
+        let frame = self.frame_cipher(key_id)?;
+
+        let aad = Self::associated_data(direction, key_id, sequence);
+        let encrypted = frame.cipher.encrypt(&Self::nonce(suite, &frame.salt, sequence), Payload { msg: data, aad: &aad })
+            .map_err(|_e| io::Error::other("Encryption failed"))?;
+        self.sequence += 1;
+
+        Ok((key_id, sequence, encrypted))
+    }
+
+    // 按帧头的密钥 ID 选择密钥解密，只接受期望序号的帧，重放、乱序或方向错误的帧都会返回错误
+    pub fn decrypt(&mut self, key_id: u8, sequence: u64, data: &[u8]) -> io::Result<Vec<u8>> {
+        if sequence < self.sequence {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Replayed frame: sequence {} already received", sequence),
+            ));
+        }
+        if sequence > self.sequence {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Out-of-order frame: expected sequence {}, got {}", self.sequence, sequence),
+            ));
+        }
+
+        let (suite, direction) = (self.suite, self.direction);
+        let frame = self.frame_cipher(key_id)?;
+
+        let aad = Self::associated_data(direction, key_id, sequence);
+        let decrypted = frame.cipher.decrypt(&Self::nonce(suite, &frame.salt, sequence), Payload { msg: data, aad: &aad })
+            .map_err(|_e| io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Decryption failed: frame tampered or sent in the wrong direction",
+            ))?;
+        self.sequence += 1;
+
+        Ok(decrypted)
     }
 }
 
 
+// 解析密钥文本：64 位十六进制或 base64 编码的 32 字节
+pub fn parse_key(text: &str) -> io::Result<Key> {
+    let text = text.trim();
+
+    let bytes = if text.len() == KEY_LEN * 2 && text.chars().all(|c| c.is_ascii_hexdigit()) {
+        hex::decode(text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?
+    } else {
+        BASE64.decode(text)
+            .map_err(|_e| io::Error::new(io::ErrorKind::InvalidData, "Key is neither 64 hex digits nor valid base64"))?
+    };
+
+    bytes.try_into().map_err(|b: Vec<u8>| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Key must be {} bytes, got {}", KEY_LEN, b.len()),
+        )
+    })
+}
+
+// 生成随机密钥
+pub fn generate_key() -> Key {
+    let mut key = [0u8; KEY_LEN];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+// 口令派生密钥的默认盐，建议每个部署配置自己的盐
+pub const DEFAULT_PASSPHRASE_SALT: &str = "PortForward passphrase salt";
 
-// 加密数据并添加4字节长度头
+// Argon2id 参数固定写死，两端必须一致才能派生出相同的密钥
+const ARGON2_MEMORY_KIB: u32 = 64 * 1024;
+const ARGON2_ITERATIONS: u32 = 3;
+const ARGON2_PARALLELISM: u32 = 1;
+
+// 使用 Argon2id 从口令派生密钥
+pub fn derive_key_from_passphrase(passphrase: &str, salt: &str) -> io::Result<Key> {
+    if passphrase.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Passphrase is empty"));
+    }
+    if salt.len() < argon2::MIN_SALT_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Passphrase salt must be at least {} bytes", argon2::MIN_SALT_LEN),
+        ));
+    }
+
+    let params = Params::new(ARGON2_MEMORY_KIB, ARGON2_ITERATIONS, ARGON2_PARALLELISM, Some(KEY_LEN))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+    let mut key = [0u8; KEY_LEN];
+    argon2.hash_password_into(passphrase.as_bytes(), salt.as_bytes(), &mut key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Derive key failure: {}", e)))?;
+
+    Ok(key)
+}
+
+// 从密钥文件读取：文本（十六进制/base64）或 32 字节原始二进制
+pub fn load_key_file(path: &str) -> io::Result<Key> {
+    let content = std::fs::read(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Read key file {} failure: {}", path, e)))?;
+
+    if let Some(key) = std::str::from_utf8(&content).ok().and_then(|text| parse_key(text).ok()) {
+        return Ok(key);
+    }
+
+    content.try_into().map_err(|_e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Key file {} does not contain a valid {} byte key", path, KEY_LEN),
+        )
+    })
+}
+
+
+// 写入密钥文件（十六进制文本），Unix 下权限为 0600，已存在时不覆盖
+pub fn write_key_file(path: &str, text: &str) -> io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Create key file {} failure: {}", path, e)))?;
+    file.write_all(text.as_bytes())?;
+    file.write_all(b"\n")?;
+    Ok(())
+}
+
+
+// 加密数据并添加4字节长度头、1字节密钥 ID 和8字节帧序号
 pub async fn encrypt_and_prepend_length(
     data: &[u8],
-    ctx: &SimpleEncryptionContext,
+    ctx: &mut SimpleEncryptionContext,
 ) -> io::Result<Vec<u8>> {
-    // 1. 加密数据
-    let encrypted_data = ctx.encrypt(data)?;
-    
+    // 1. 按填充策略填充，data 是一个编码后的帧，必须能放进一个加密帧
+    let frame = ctx.padding.pad(data)?;
 
-    // 2. 添加4字节长度头
+    // 2. 加密数据
+    let (key_id, sequence, encrypted_data) = ctx.encrypt(&frame)?;
+
+    // 3. 添加帧头
+    let mut result = Vec::with_capacity(FRAME_HEADER_LEN + encrypted_data.len());
     let data_len = encrypted_data.len() as u32;
-    let mut result = Vec::with_capacity(4 + encrypted_data.len());
     result.extend_from_slice(&data_len.to_be_bytes());
+    result.push(key_id);
+    result.extend_from_slice(&sequence.to_be_bytes());
     result.extend_from_slice(&encrypted_data);
-    
+
     Ok(result)
 }
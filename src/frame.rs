@@ -0,0 +1,129 @@
+use std::io;
+
+// 帧类型，位于加密帧明文（去掉填充后）的首字节
+const FRAME_DATA: u8 = 0;
+const FRAME_PING: u8 = 1;
+const FRAME_PONG: u8 = 2;
+const FRAME_EOF: u8 = 3;
+const FRAME_CLOSE: u8 = 4;
+const FRAME_WINDOW_UPDATE: u8 = 5;
+const FRAME_OPEN: u8 = 6;
+const FRAME_STREAM: u8 = 7;
+const FRAME_REGISTER: u8 = 8;
+
+// 帧编码在数据之外的最大开销：流帧的类型、流 ID 和内层帧类型
+pub const MAX_FRAME_OVERHEAD: usize = 1 + 4 + 1;
+
+// 隧道中的帧：数据帧之外还有保活、半关闭、关闭原因和流控窗口更新；
+// 多路复用会话中，流上的帧用 Stream 包装并带上流 ID
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Data(Vec<u8>),
+    Ping(u64),
+    Pong(u64),
+    // 发送方向不再有数据，另一个方向不受影响
+    Eof,
+    // 出错关闭连接，附带原因
+    Close(String),
+    // 接收方已转发的字节数，发送方据此增加可发送额度
+    WindowUpdate(u32),
+    // 打开一个新的流，只出现在 Stream 中
+    Open,
+    // 多路复用流上的帧：流 ID | 内层帧
+    Stream(u32, Box<Frame>),
+    // 反向隧道：内网端注册的转发名称，公网端原样返回表示接受
+    Register(String),
+}
+
+impl Frame {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Frame::Data(data) => {
+                let mut encoded = Vec::with_capacity(1 + data.len());
+                encoded.push(FRAME_DATA);
+                encoded.extend_from_slice(data);
+                encoded
+            }
+            Frame::Ping(value) => [&[FRAME_PING][..], &value.to_be_bytes()].concat(),
+            Frame::Pong(value) => [&[FRAME_PONG][..], &value.to_be_bytes()].concat(),
+            Frame::Eof => vec![FRAME_EOF],
+            Frame::Close(reason) => [&[FRAME_CLOSE][..], reason.as_bytes()].concat(),
+            Frame::WindowUpdate(increment) => [&[FRAME_WINDOW_UPDATE][..], &increment.to_be_bytes()].concat(),
+            Frame::Open => vec![FRAME_OPEN],
+            Frame::Stream(id, frame) => [&[FRAME_STREAM][..], &id.to_be_bytes(), &frame.encode()].concat(),
+            Frame::Register(name) => [&[FRAME_REGISTER][..], name.as_bytes()].concat(),
+        }
+    }
+
+    // 把过长的数据帧（包括流中的数据帧）拆成多帧、截短过长的关闭原因，保证每帧编码后能放进一个加密帧
+    pub fn split(self, max_len: usize) -> Vec<Frame> {
+        match self {
+            Frame::Data(data) if data.len() > max_len => {
+                data.chunks(max_len).map(|chunk| Frame::Data(chunk.to_vec())).collect()
+            }
+            Frame::Close(mut reason) if reason.len() > max_len => {
+                let mut end = max_len;
+                while !reason.is_char_boundary(end) {
+                    end -= 1;
+                }
+                reason.truncate(end);
+                vec![Frame::Close(reason)]
+            }
+            Frame::Stream(id, frame) => {
+                frame.split(max_len).into_iter().map(|frame| Frame::Stream(id, Box::new(frame))).collect()
+            }
+            frame => vec![frame],
+        }
+    }
+
+    // 对数据帧（包括流中的数据帧）的内容做变换，用于压缩和解压
+    pub fn map_data<F>(self, f: F) -> io::Result<Frame>
+    where
+        F: FnOnce(Vec<u8>) -> io::Result<Vec<u8>>,
+    {
+        match self {
+            Frame::Data(data) => Ok(Frame::Data(f(data)?)),
+            Frame::Stream(id, frame) => Ok(Frame::Stream(id, Box::new(frame.map_data(f)?))),
+            frame => Ok(frame),
+        }
+    }
+
+    pub fn decode(mut plaintext: Vec<u8>) -> io::Result<Frame> {
+        if plaintext.is_empty() {
+            return Err(invalid_frame("Empty frame".to_string()));
+        }
+
+        let frame_type = plaintext[0];
+        let body = &plaintext[1..];
+        let frame = match frame_type {
+            FRAME_DATA => {
+                plaintext.remove(0);
+                Frame::Data(plaintext)
+            }
+            FRAME_PING => Frame::Ping(u64::from_be_bytes(fixed(body, "ping")?)),
+            FRAME_PONG => Frame::Pong(u64::from_be_bytes(fixed(body, "pong")?)),
+            FRAME_EOF if body.is_empty() => Frame::Eof,
+            FRAME_CLOSE => Frame::Close(String::from_utf8_lossy(body).to_string()),
+            FRAME_WINDOW_UPDATE => Frame::WindowUpdate(u32::from_be_bytes(fixed(body, "window update")?)),
+            FRAME_OPEN if body.is_empty() => Frame::Open,
+            FRAME_STREAM if body.len() > 4 => {
+                let id = u32::from_be_bytes(fixed(&body[..4], "stream")?);
+                match Frame::decode(body[4..].to_vec())? {
+                    Frame::Stream(..) => return Err(invalid_frame("Nested stream frame".to_string())),
+                    frame => Frame::Stream(id, Box::new(frame)),
+                }
+            }
+            FRAME_REGISTER => Frame::Register(String::from_utf8_lossy(body).to_string()),
+            _ => return Err(invalid_frame(format!("Unknown frame type {}", frame_type))),
+        };
+        Ok(frame)
+    }
+}
+
+fn fixed<const N: usize>(body: &[u8], name: &str) -> io::Result<[u8; N]> {
+    body.try_into().map_err(|_e| invalid_frame(format!("Malformed {} frame", name)))
+}
+
+fn invalid_frame(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,370 @@
+use aes_gcm::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::encryption::{
+    CipherSuite, Direction, Key, KeySchedule, NONCE_SALT_LEN, SharedKeyRing, SimpleEncryptionContext, read_key_ring,
+};
+use crate::compression::Compression;
+use crate::identity::{AllowedIdentities, LocalIdentity, encode_proof};
+use crate::preamble::{self, FEATURE_COMPRESSION, FEATURE_MULTIPLEX, Negotiated};
+use std::io;
+use std::sync::Arc;
+
+// 握手流程（发起方为远程加密侧，响应方为本地加密侧），开始前先交换连接前导（见 preamble.rs）：
+//   发起方 -> 响应方: 发起方临时公钥 | 加密套件 | 密钥 ID | 压缩算法 | 会话模式
+//   响应方 -> 发起方: 状态 | 加密套件 | 协商的压缩算法 | 响应方临时公钥 | 响应方确认码
+//   发起方 -> 响应方: 发起方确认码 | 加密的身份证明
+//   响应方 -> 发起方: 身份校验结果
+// 确认码由 X25519 共享密钥经 HKDF 派生，密钥 ID 对应的 PSK 作为 HKDF 盐，
+// 不持有 PSK 的一方无法算出确认码，临时密钥保证前向安全。
+// 帧密钥按帧头的密钥 ID 用同样方式派生，密钥轮换时无需重新握手。
+// 身份证明用握手派生的一次性密钥加密，令牌不会以明文出现在链路上。
+
+const PUBLIC_KEY_LEN: usize = 32;
+const TAG_LEN: usize = 32;
+const PROTOCOL_LABEL: &[u8] = b"PortForward handshake v1";
+
+const CLIENT_HELLO_LEN: usize = PUBLIC_KEY_LEN + 4;
+const SERVER_HELLO_LEN: usize = 3 + PUBLIC_KEY_LEN + TAG_LEN;
+
+// 响应方状态
+const STATUS_OK: u8 = 0;
+const STATUS_CIPHER_MISMATCH: u8 = 1;
+const STATUS_UNKNOWN_KEY: u8 = 2;
+const STATUS_IDENTITY_REJECTED: u8 = 3;
+
+// 会话模式：每个客户端连接一条加密连接，或在一条加密连接上多路复用
+const MODE_SINGLE: u8 = 0;
+const MODE_MULTIPLEX: u8 = 1;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// 握手完成后两个方向各自的加密上下文
+pub struct SessionKeys {
+    pub sender: SimpleEncryptionContext,
+    pub receiver: SimpleEncryptionContext,
+    // 响应方校验通过的对端身份，未配置允许列表时为空
+    pub peer_identity: Option<String>,
+    // 两端协商一致的压缩算法
+    pub compression: Compression,
+    // 发起方请求了多路复用会话
+    pub multiplexed: bool,
+}
+
+// 会话秘密：临时共享密钥和握手记录摘要，用于按密钥 ID 派生帧密钥
+struct SessionSecret {
+    shared: [u8; 32],
+    transcript: [u8; 32],
+}
+
+impl SessionSecret {
+    fn hkdf(&self, psk: &Key) -> Hkdf<Sha256> {
+        Hkdf::<Sha256>::new(Some(psk), &self.shared)
+    }
+}
+
+impl KeySchedule for SessionSecret {
+    fn frame_key(&self, psk: &Key, direction: Direction) -> (Key, [u8; NONCE_SALT_LEN]) {
+        let hkdf = self.hkdf(psk);
+        match direction {
+            Direction::InitiatorToResponder => (
+                expand(&hkdf, b"initiator key", &self.transcript),
+                expand(&hkdf, b"initiator nonce", &self.transcript),
+            ),
+            Direction::ResponderToInitiator => (
+                expand(&hkdf, b"responder key", &self.transcript),
+                expand(&hkdf, b"responder nonce", &self.transcript),
+            ),
+        }
+    }
+}
+
+// 作为发起方完成握手，使用本侧当前生效的密钥认证，并出示配置的身份
+pub async fn initiate<S>(
+    stream: &mut S,
+    keyring: &SharedKeyRing,
+    suite: CipherSuite,
+    compression: Compression,
+    multiplex: bool,
+    identity: Option<&LocalIdentity>,
+) -> io::Result<SessionKeys>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let negotiated = preamble::initiate(stream).await?;
+    let compression = offered_compression(&negotiated, compression);
+    if multiplex && !negotiated.supports(FEATURE_MULTIPLEX) {
+        return Err(handshake_error("Peer does not support multiplexing, upgrade PortForward on the peer".to_string()));
+    }
+    let mode = if multiplex { MODE_MULTIPLEX } else { MODE_SINGLE };
+    let (key_id, psk) = read_key_ring(keyring).active();
+
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let initiator_public = PublicKey::from(&secret);
+
+    let mut hello = Vec::with_capacity(CLIENT_HELLO_LEN);
+    hello.extend_from_slice(initiator_public.as_bytes());
+    hello.push(suite.id());
+    hello.push(key_id);
+    hello.push(compression.id());
+    hello.push(mode);
+    stream.write_all(&hello).await?;
+
+    let mut reply = [0u8; SERVER_HELLO_LEN];
+    stream.read_exact(&mut reply).await
+        .map_err(|e| io::Error::new(e.kind(), format!("Peer closed during handshake: {}", e)))?;
+
+    match reply[0] {
+        STATUS_OK => {}
+        STATUS_CIPHER_MISMATCH => return Err(cipher_mismatch(suite, reply[1])),
+        STATUS_UNKNOWN_KEY => {
+            return Err(handshake_error(format!("Peer does not accept key id {}", key_id)));
+        }
+        status => {
+            return Err(handshake_error(format!("Peer rejected handshake with status {}", status)));
+        }
+    }
+
+    let agreed = Compression::from_id(reply[2])
+        .ok_or_else(|| handshake_error(format!("Peer chose unknown compression {}", reply[2])))?;
+    let responder_public = public_key(&reply[3..3 + PUBLIC_KEY_LEN]);
+
+    let transcript = transcript_hash(&negotiated, suite, key_id, [compression.id(), agreed.id(), mode], &initiator_public, &responder_public);
+    let session = session_secret(secret.diffie_hellman(&responder_public), transcript)?;
+    let hkdf = session.hkdf(&psk);
+
+    // 先验证响应方，失败则不发送自己的确认码
+    verify_tag(&expand(&hkdf, b"responder confirm", &transcript), &transcript, &reply[3 + PUBLIC_KEY_LEN..])?;
+    let mut confirm = compute_tag(&expand(&hkdf, b"initiator confirm", &transcript), &transcript).to_vec();
+    let proof = seal_proof(&expand(&hkdf, b"initiator identity", &transcript), &encode_proof(identity, &transcript))?;
+    let proof_len = u16::try_from(proof.len())
+        .map_err(|_e| handshake_error(format!("Identity proof of {} bytes is too long", proof.len())))?;
+    confirm.extend_from_slice(&proof_len.to_be_bytes());
+    confirm.extend_from_slice(&proof);
+    stream.write_all(&confirm).await?;
+
+    let mut status = [0u8; 1];
+    stream.read_exact(&mut status).await
+        .map_err(|e| io::Error::new(e.kind(), format!("Peer closed during handshake: {}", e)))?;
+    match (status[0], identity) {
+        (STATUS_OK, _) => {}
+        (STATUS_IDENTITY_REJECTED, Some(identity)) => {
+            return Err(handshake_error(format!("Peer rejected identity {}", identity.name)));
+        }
+        (STATUS_IDENTITY_REJECTED, None) => {
+            return Err(handshake_error("Peer requires an identity, configure remote_identity".to_string()));
+        }
+        (status, _) => {
+            return Err(handshake_error(format!("Peer rejected handshake with status {}", status)));
+        }
+    }
+
+    let session: Arc<dyn KeySchedule> = Arc::new(session);
+    Ok(SessionKeys {
+        sender: SimpleEncryptionContext::new(suite, Direction::InitiatorToResponder, session.clone(), keyring.clone()),
+        receiver: SimpleEncryptionContext::new(suite, Direction::ResponderToInitiator, session, keyring.clone()),
+        peer_identity: None,
+        compression: agreed,
+        multiplexed: multiplex,
+    })
+}
+
+// 作为响应方完成握手，加密套件不一致、密钥 ID 未知、发起方验证失败或身份不在允许列表时返回错误
+pub async fn respond<S>(
+    stream: &mut S,
+    keyring: &SharedKeyRing,
+    suite: CipherSuite,
+    compression: Compression,
+    allowed: &AllowedIdentities,
+) -> io::Result<SessionKeys>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let negotiated = preamble::respond(stream).await?;
+    let compression = offered_compression(&negotiated, compression);
+
+    let mut hello = [0u8; CLIENT_HELLO_LEN];
+    stream.read_exact(&mut hello).await
+        .map_err(|e| io::Error::new(e.kind(), format!("Peer closed during handshake: {}", e)))?;
+    let initiator_public = public_key(&hello[..PUBLIC_KEY_LEN]);
+    let peer_suite = hello[PUBLIC_KEY_LEN];
+    let key_id = hello[PUBLIC_KEY_LEN + 1];
+    // 对端使用未知的压缩算法时按不压缩处理
+    let offered = hello[PUBLIC_KEY_LEN + 2];
+    let agreed = compression.agree(Compression::from_id(offered).unwrap_or_default());
+    let mode = hello[PUBLIC_KEY_LEN + 3];
+    if mode != MODE_SINGLE && mode != MODE_MULTIPLEX {
+        return Err(handshake_error(format!("Peer requested unknown session mode {}", mode)));
+    }
+
+    // 加密套件不一致或密钥 ID 未知时告知对端后断开，两端都能看到明确的原因
+    if peer_suite != suite.id() {
+        reject(stream, STATUS_CIPHER_MISMATCH, suite).await?;
+        return Err(cipher_mismatch(suite, peer_suite));
+    }
+
+    let psk = {
+        let ring = read_key_ring(keyring);
+        ring.get(key_id).ok_or_else(|| {
+            handshake_error(format!("Peer uses unknown key id {}, accepted key ids: {}", key_id, ring.ids()))
+        })
+    };
+    let psk = match psk {
+        Ok(psk) => psk,
+        Err(e) => {
+            reject(stream, STATUS_UNKNOWN_KEY, suite).await?;
+            return Err(e);
+        }
+    };
+
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let responder_public = PublicKey::from(&secret);
+
+    let transcript = transcript_hash(&negotiated, suite, key_id, [offered, agreed.id(), mode], &initiator_public, &responder_public);
+    let session = session_secret(secret.diffie_hellman(&initiator_public), transcript)?;
+    let hkdf = session.hkdf(&psk);
+
+    let mut reply = Vec::with_capacity(SERVER_HELLO_LEN);
+    reply.push(STATUS_OK);
+    reply.push(suite.id());
+    reply.push(agreed.id());
+    reply.extend_from_slice(responder_public.as_bytes());
+    reply.extend_from_slice(&compute_tag(&expand(&hkdf, b"responder confirm", &transcript), &transcript));
+    stream.write_all(&reply).await?;
+
+    let mut tag = [0u8; TAG_LEN];
+    stream.read_exact(&mut tag).await
+        .map_err(|e| io::Error::new(e.kind(), format!("Peer closed during handshake: {}", e)))?;
+    verify_tag(&expand(&hkdf, b"initiator confirm", &transcript), &transcript, &tag)?;
+
+    let mut proof_len = [0u8; 2];
+    stream.read_exact(&mut proof_len).await
+        .map_err(|e| io::Error::new(e.kind(), format!("Peer closed during handshake: {}", e)))?;
+    let mut proof = vec![0u8; u16::from_be_bytes(proof_len) as usize];
+    stream.read_exact(&mut proof).await
+        .map_err(|e| io::Error::new(e.kind(), format!("Peer closed during handshake: {}", e)))?;
+    let proof = open_proof(&expand(&hkdf, b"initiator identity", &transcript), &proof)?;
+
+    // 未配置允许列表时不校验身份，保持只凭密钥认证
+    let peer_identity = if allowed.is_empty() {
+        None
+    } else {
+        match allowed.verify_proof(&proof, &transcript) {
+            Ok(name) => Some(name),
+            Err(msg) => {
+                stream.write_all(&[STATUS_IDENTITY_REJECTED]).await?;
+                return Err(handshake_error(msg));
+            }
+        }
+    };
+    stream.write_all(&[STATUS_OK]).await?;
+
+    let session: Arc<dyn KeySchedule> = Arc::new(session);
+    Ok(SessionKeys {
+        sender: SimpleEncryptionContext::new(suite, Direction::ResponderToInitiator, session.clone(), keyring.clone()),
+        receiver: SimpleEncryptionContext::new(suite, Direction::InitiatorToResponder, session, keyring.clone()),
+        peer_identity,
+        compression: agreed,
+        multiplexed: mode == MODE_MULTIPLEX,
+    })
+}
+
+
+async fn reject<S>(stream: &mut S, status: u8, suite: CipherSuite) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut reply = [0u8; SERVER_HELLO_LEN];
+    reply[0] = status;
+    reply[1] = suite.id();
+    stream.write_all(&reply).await
+}
+
+fn public_key(bytes: &[u8]) -> PublicKey {
+    let mut key = [0u8; PUBLIC_KEY_LEN];
+    key.copy_from_slice(bytes);
+    PublicKey::from(key)
+}
+
+// 对端不支持压缩时按不压缩处理
+fn offered_compression(negotiated: &Negotiated, compression: Compression) -> Compression {
+    if negotiated.supports(FEATURE_COMPRESSION) { compression } else { Compression::None }
+}
+
+// 连接前导、压缩协商结果和会话模式也计入握手记录，防止被中间人降级
+fn transcript_hash(
+    negotiated: &Negotiated,
+    suite: CipherSuite,
+    key_id: u8,
+    options: [u8; 3],
+    initiator: &PublicKey,
+    responder: &PublicKey,
+) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(PROTOCOL_LABEL);
+    hasher.update(negotiated.transcript);
+    hasher.update([suite.id(), key_id, options[0], options[1], options[2]]);
+    hasher.update(initiator.as_bytes());
+    hasher.update(responder.as_bytes());
+    hasher.finalize().into()
+}
+
+fn session_secret(shared: x25519_dalek::SharedSecret, transcript: [u8; 32]) -> io::Result<SessionSecret> {
+    // 拒绝低阶点产生的全零共享密钥
+    if !shared.was_contributory() {
+        return Err(handshake_error("Peer sent an invalid public key".to_string()));
+    }
+
+    Ok(SessionSecret { shared: shared.to_bytes(), transcript })
+}
+
+fn expand<const N: usize>(hkdf: &Hkdf<Sha256>, label: &[u8], transcript: &[u8; 32]) -> [u8; N] {
+    let mut out = [0u8; N];
+    // N 远小于 HKDF 输出上限，不会失败
+    hkdf.expand_multi_info(&[PROTOCOL_LABEL, label, transcript], &mut out)
+        .expect("HKDF output length is valid");
+    out
+}
+
+fn compute_tag(key: &Key, transcript: &[u8; 32]) -> [u8; TAG_LEN] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(transcript);
+    mac.finalize().into_bytes().into()
+}
+
+// 常量时间比较确认码
+fn verify_tag(key: &Key, transcript: &[u8; 32], tag: &[u8]) -> io::Result<()> {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(transcript);
+    mac.verify_slice(tag)
+        .map_err(|_e| handshake_error("Handshake authentication failed, peer key mismatch".to_string()))
+}
+
+// 身份证明密钥只用于这一条消息，使用固定 nonce
+fn seal_proof(key: &Key, proof: &[u8]) -> io::Result<Vec<u8>> {
+    ChaCha20Poly1305::new(key.into())
+        .encrypt(&Nonce::default(), proof)
+        .map_err(|_e| handshake_error("Failed to encrypt identity proof".to_string()))
+}
+
+fn open_proof(key: &Key, sealed: &[u8]) -> io::Result<Vec<u8>> {
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(&Nonce::default(), sealed)
+        .map_err(|_e| handshake_error("Identity proof tampered".to_string()))
+}
+
+fn cipher_mismatch(local: CipherSuite, peer: u8) -> io::Error {
+    let peer = CipherSuite::from_id(peer)
+        .map(|suite| suite.name().to_string())
+        .unwrap_or_else(|| format!("unknown cipher {}", peer));
+    handshake_error(format!("Cipher mismatch: configured {}, peer uses {}", local.name(), peer))
+}
+
+fn handshake_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, msg)
+}
@@ -0,0 +1,210 @@
+use std::io;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::encryption::{Key, load_key_file, parse_key};
+
+// 连接远程加密侧时出示的身份：Ed25519 私钥或命名令牌
+#[derive(Deserialize, Clone)]
+pub struct IdentityConfig {
+    pub name: String,
+    pub private_key: Option<String>,
+    pub private_key_file: Option<String>,
+    pub token: Option<String>,
+}
+
+// 允许连接本地监听的身份：Ed25519 公钥、命名令牌或客户端证书 SHA-256 指纹
+#[derive(Deserialize, Clone)]
+pub struct AllowedIdentity {
+    pub name: String,
+    pub public_key: Option<String>,
+    pub token: Option<String>,
+    pub cert_sha256: Option<String>,
+}
+
+pub enum Credential {
+    Token(Vec<u8>),
+    Ed25519(Box<SigningKey>),
+}
+
+pub struct LocalIdentity {
+    pub name: String,
+    pub credential: Credential,
+}
+
+enum IdentityVerifier {
+    Token(Vec<u8>),
+    Ed25519(VerifyingKey),
+    CertSha256([u8; 32]),
+}
+
+pub struct AllowedIdentities {
+    entries: Vec<(String, IdentityVerifier)>,
+}
+
+// 身份证明的类型
+const KIND_NONE: u8 = 0;
+const KIND_TOKEN: u8 = 1;
+const KIND_ED25519: u8 = 2;
+
+const SIGNATURE_LABEL: &[u8] = b"PortForward identity v1";
+// 身份证明加密后附加的认证标签长度；加密后的证明以 2 字节长度发送，令牌长度按此留出余量
+const SEALED_PROOF_OVERHEAD: usize = 16;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+pub fn load_identity(config: &IdentityConfig) -> io::Result<LocalIdentity> {
+    if config.name.is_empty() || config.name.len() > u8::MAX as usize {
+        return Err(invalid(format!("Identity name must be 1 to {} bytes", u8::MAX)));
+    }
+
+    // 类型、名字长度、名字、令牌长度、令牌和认证标签一起不能超过 u16
+    let max_token = u16::MAX as usize - (1 + 1 + config.name.len() + 2 + SEALED_PROOF_OVERHEAD);
+    let credential = match (&config.private_key, &config.private_key_file, &config.token) {
+        (Some(text), None, None) => Credential::Ed25519(Box::new(SigningKey::from_bytes(&parse_key(text)?))),
+        (None, Some(path), None) => Credential::Ed25519(Box::new(SigningKey::from_bytes(&load_key_file(path)?))),
+        (None, None, Some(token)) if !token.is_empty() && token.len() <= max_token => {
+            Credential::Token(token.as_bytes().to_vec())
+        }
+        (None, None, Some(_)) => return Err(invalid(format!("Identity token must be 1 to {} bytes", max_token))),
+        _ => {
+            return Err(invalid(format!(
+                "Identity {} needs exactly one of private_key, private_key_file or token",
+                config.name
+            )));
+        }
+    };
+
+    Ok(LocalIdentity { name: config.name.clone(), credential })
+}
+
+pub fn load_allowed_identities(entries: &[AllowedIdentity]) -> io::Result<AllowedIdentities> {
+    let mut loaded = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let verifier = match (&entry.public_key, &entry.token, &entry.cert_sha256) {
+            (Some(text), None, None) => {
+                let key = VerifyingKey::from_bytes(&parse_key(text)?)
+                    .map_err(|e| invalid(format!("Identity {}: invalid public key: {}", entry.name, e)))?;
+                IdentityVerifier::Ed25519(key)
+            }
+            (None, Some(token), None) => IdentityVerifier::Token(token.as_bytes().to_vec()),
+            (None, None, Some(fingerprint)) => {
+                let fingerprint = parse_fingerprint(fingerprint)
+                    .ok_or_else(|| invalid(format!("Identity {}: cert_sha256 must be 64 hex digits", entry.name)))?;
+                IdentityVerifier::CertSha256(fingerprint)
+            }
+            _ => {
+                return Err(invalid(format!(
+                    "Identity {} needs exactly one of public_key, token or cert_sha256",
+                    entry.name
+                )));
+            }
+        };
+        loaded.push((entry.name.clone(), verifier));
+    }
+    Ok(AllowedIdentities { entries: loaded })
+}
+
+// 证书指纹允许带冒号分隔
+fn parse_fingerprint(text: &str) -> Option<[u8; 32]> {
+    let hex_text: String = text.chars().filter(|c| *c != ':').collect();
+    hex::decode(hex_text).ok()?.try_into().ok()
+}
+
+pub fn public_key_of(private_key: &Key) -> Key {
+    SigningKey::from_bytes(private_key).verifying_key().to_bytes()
+}
+
+// 编码身份证明：类型 | 名称长度 | 名称 | 证明；Ed25519 对握手记录摘要签名，防止被转移到其他会话
+pub fn encode_proof(identity: Option<&LocalIdentity>, transcript: &[u8; 32]) -> Vec<u8> {
+    let Some(identity) = identity else {
+        return vec![KIND_NONE];
+    };
+
+    let mut proof = Vec::new();
+    match &identity.credential {
+        Credential::Token(token) => {
+            proof.push(KIND_TOKEN);
+            push_name(&mut proof, &identity.name);
+            proof.extend_from_slice(&(token.len() as u16).to_be_bytes());
+            proof.extend_from_slice(token);
+        }
+        Credential::Ed25519(key) => {
+            proof.push(KIND_ED25519);
+            push_name(&mut proof, &identity.name);
+            proof.extend_from_slice(key.verifying_key().as_bytes());
+            proof.extend_from_slice(&key.sign(&signed_message(transcript)).to_bytes());
+        }
+    }
+    proof
+}
+
+fn push_name(proof: &mut Vec<u8>, name: &str) {
+    proof.push(name.len() as u8);
+    proof.extend_from_slice(name.as_bytes());
+}
+
+fn signed_message(transcript: &[u8; 32]) -> Vec<u8> {
+    [SIGNATURE_LABEL, transcript.as_slice()].concat()
+}
+
+impl AllowedIdentities {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // 校验对端的身份证明，返回匹配的身份名称
+    pub fn verify_proof(&self, proof: &[u8], transcript: &[u8; 32]) -> Result<String, String> {
+        let (&kind, rest) = proof.split_first().ok_or("Empty identity proof")?;
+        if kind == KIND_NONE {
+            return Err("Peer presented no identity".to_string());
+        }
+
+        let (&name_len, rest) = rest.split_first().ok_or("Truncated identity proof")?;
+        let name_len = name_len as usize;
+        if rest.len() < name_len {
+            return Err("Truncated identity proof".to_string());
+        }
+        let name = String::from_utf8_lossy(&rest[..name_len]).to_string();
+        let rest = &rest[name_len..];
+
+        let verifier = self.entries.iter()
+            .find(|(allowed, _)| *allowed == name)
+            .map(|(_, verifier)| verifier)
+            .ok_or_else(|| format!("Identity {} is not allowed", name))?;
+
+        let valid = match (kind, verifier) {
+            (KIND_TOKEN, IdentityVerifier::Token(expected)) => {
+                rest.len() >= 2 && rest[2..].ct_eq(expected).into()
+                    && u16::from_be_bytes([rest[0], rest[1]]) as usize == expected.len()
+            }
+            (KIND_ED25519, IdentityVerifier::Ed25519(expected)) => {
+                rest.len() == 32 + Signature::BYTE_SIZE
+                    && rest[..32] == expected.as_bytes()[..]
+                    && Signature::from_slice(&rest[32..])
+                        .is_ok_and(|signature| expected.verify(&signed_message(transcript), &signature).is_ok())
+            }
+            _ => false,
+        };
+
+        if valid {
+            Ok(name)
+        } else {
+            Err(format!("Identity {} failed verification", name))
+        }
+    }
+
+    // 按客户端证书指纹匹配身份，用于 TLS 监听
+    pub fn verify_certificate(&self, certificate: &[u8]) -> Result<String, String> {
+        let fingerprint: [u8; 32] = Sha256::digest(certificate).into();
+        self.entries.iter()
+            .find(|(_, verifier)| matches!(verifier, IdentityVerifier::CertSha256(expected) if *expected == fingerprint))
+            .map(|(name, _)| name.clone())
+            .ok_or_else(|| format!("Client certificate {} is not allowed", hex::encode(fingerprint)))
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+
+use serde::Deserialize;
+use tklog::{async_error, async_info};
+
+use crate::Forward;
+use crate::encryption::{
+    DEFAULT_PASSPHRASE_SALT, Key, KeyRing, SharedKeyRing, derive_key_from_passphrase, load_key_file, parse_key,
+};
+
+// 密钥列表中的一项，按 ID 区分，用于不中断连接的密钥轮换
+#[derive(Deserialize, Clone)]
+pub struct KeyEntry {
+    pub id: u8,
+    pub key: Option<String>,
+    pub key_file: Option<String>,
+    pub passphrase: Option<String>,
+    pub passphrase_salt: Option<String>,
+}
+
+// 检查配置文件修改时间的间隔
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// 启动时加载的两侧密钥集合，重新加载时原地替换
+#[derive(Clone)]
+pub struct ForwardKeys {
+    pub local: Option<SharedKeyRing>,
+    pub remote: Option<SharedKeyRing>,
+}
+
+// 一侧的密钥来源：内联密钥、密钥文件或口令，三者只能配置其一
+struct KeySource<'a> {
+    key: &'a Option<String>,
+    key_file: &'a Option<String>,
+    passphrase: &'a Option<String>,
+    passphrase_salt: &'a Option<String>,
+}
+
+impl KeySource<'_> {
+    fn is_configured(&self) -> bool {
+        self.key.is_some() || self.key_file.is_some() || self.passphrase.is_some()
+    }
+}
+
+impl<'a> From<&'a KeyEntry> for KeySource<'a> {
+    fn from(entry: &'a KeyEntry) -> Self {
+        KeySource {
+            key: &entry.key,
+            key_file: &entry.key_file,
+            passphrase: &entry.passphrase,
+            passphrase_salt: &entry.passphrase_salt,
+        }
+    }
+}
+
+// 读取一个密钥来源，prefix 为配置项前缀，用于错误信息
+fn load_key_source(prefix: &str, source: KeySource) -> Result<Option<Key>, String> {
+    let configured = [source.key.is_some(), source.key_file.is_some(), source.passphrase.is_some()]
+        .iter()
+        .filter(|c| **c)
+        .count();
+    if configured > 1 {
+        return Err(format!(
+            "only one of {}key, {}key_file and {}passphrase may be configured",
+            prefix, prefix, prefix
+        ));
+    }
+
+    let key = if let Some(text) = source.key {
+        parse_key(text).map_err(|e| format!("{}key: {}", prefix, e))?
+    } else if let Some(path) = source.key_file {
+        load_key_file(path).map_err(|e| format!("{}key_file: {}", prefix, e))?
+    } else if let Some(passphrase) = source.passphrase {
+        let salt = source.passphrase_salt.as_deref().unwrap_or(DEFAULT_PASSPHRASE_SALT);
+        derive_key_from_passphrase(passphrase, salt).map_err(|e| format!("{}passphrase: {}", prefix, e))?
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(key))
+}
+
+// 读取一侧的密钥配置：单个密钥（ID 为 0）或带 ID 的密钥列表，开启加密时必须配置密钥
+fn load_side_keys(
+    side: &str,
+    encryption: bool,
+    single: KeySource,
+    entries: &[KeyEntry],
+    active: Option<u8>,
+) -> Result<Option<KeyRing>, String> {
+    // 未开启加密时忽略密钥，避免无谓的口令派生
+    if !encryption {
+        return Ok(None);
+    }
+
+    if single.is_configured() && !entries.is_empty() {
+        return Err(format!("{}_keys cannot be combined with a single {} key", side, side));
+    }
+
+    let mut keys = BTreeMap::new();
+    if entries.is_empty() {
+        match load_key_source(&format!("{}_", side), single)? {
+            Some(key) => {
+                keys.insert(0, key);
+            }
+            None => {
+                return Err(format!(
+                    "{}_encryption is enabled but none of {}_key, {}_key_file, {}_passphrase or {}_keys is configured",
+                    side, side, side, side, side
+                ));
+            }
+        }
+    } else {
+        for entry in entries {
+            let prefix = format!("{}_keys id {}: ", side, entry.id);
+            let key = load_key_source(&prefix, entry.into())?
+                .ok_or_else(|| format!("{}no key, key_file or passphrase configured", prefix))?;
+            if keys.insert(entry.id, key).is_some() {
+                return Err(format!("{}_keys contains key id {} more than once", side, entry.id));
+            }
+        }
+    }
+
+    // 只有一个密钥时自动生效，多个密钥时必须指定发送用的密钥
+    let active = match active {
+        Some(id) => id,
+        None if keys.len() == 1 => *keys.keys().next().unwrap(),
+        None => return Err(format!("{}_active_key is required when several {} keys are configured", side, side)),
+    };
+
+    KeyRing::new(keys, active)
+        .map(Some)
+        .map_err(|e| format!("{}_active_key: {}", side, e))
+}
+
+fn load_key_rings(forward: &Forward) -> io::Result<(Option<KeyRing>, Option<KeyRing>)> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, format!("[ {} ] {}", forward.name, msg));
+
+    let local = load_side_keys(
+        "local",
+        forward.local_encryption,
+        KeySource {
+            key: &forward.local_key,
+            key_file: &forward.local_key_file,
+            passphrase: &forward.local_passphrase,
+            passphrase_salt: &forward.local_passphrase_salt,
+        },
+        &forward.local_keys,
+        forward.local_active_key,
+    ).map_err(invalid)?;
+
+    let remote = load_side_keys(
+        "remote",
+        forward.remote_encryption,
+        KeySource {
+            key: &forward.remote_key,
+            key_file: &forward.remote_key_file,
+            passphrase: &forward.remote_passphrase,
+            passphrase_salt: &forward.remote_passphrase_salt,
+        },
+        &forward.remote_keys,
+        forward.remote_active_key,
+    ).map_err(invalid)?;
+
+    Ok((local, remote))
+}
+
+pub fn load_forward_keys(forward: &Forward) -> io::Result<ForwardKeys> {
+    let (local, remote) = load_key_rings(forward)?;
+    Ok(ForwardKeys {
+        local: local.map(|ring| Arc::new(RwLock::new(ring))),
+        remote: remote.map(|ring| Arc::new(RwLock::new(ring))),
+    })
+}
+
+// 用新配置替换已加载的密钥集合，加密开关的变化需要重启才能生效
+pub fn reload_forward_keys(keys: &ForwardKeys, forward: &Forward) -> io::Result<()> {
+    let (local, remote) = load_key_rings(forward)?;
+
+    if keys.local.is_some() != local.is_some() || keys.remote.is_some() != remote.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("[ {} ] encryption settings changed, restart to apply", forward.name),
+        ));
+    }
+
+    for (shared, ring) in [(&keys.local, local), (&keys.remote, remote)] {
+        if let (Some(shared), Some(ring)) = (shared, ring) {
+            *shared.write().unwrap_or_else(PoisonError::into_inner) = ring;
+        }
+    }
+    Ok(())
+}
+
+// 重新读取配置文件中的密钥，已建立的连接从下一帧起使用新的密钥集合
+async fn reload_keys(config_path: &str, forwards: &[(String, ForwardKeys)]) {
+    let config = match std::fs::read_to_string(config_path)
+        .map_err(|e| e.to_string())
+        .and_then(|content| toml::from_str::<crate::Config>(&content).map_err(|e| e.to_string()))
+    {
+        Ok(config) => config,
+        Err(e) => {
+            async_error!("Reload keys failure: ",e);
+            return;
+        }
+    };
+
+    for (name, keys) in forwards {
+        let Some(forward) = config.forwards.iter().find(|f| &f.name == name) else {
+            async_error!("[ ",name," ] not found in config, keys unchanged");
+            continue;
+        };
+        match reload_forward_keys(keys, forward) {
+            Ok(()) => async_info!("[ ",name," ] keys reloaded"),
+            Err(e) => async_error!("Reload keys failure: ",e.to_string()),
+        }
+    }
+}
+
+// 收到 SIGHUP 时重新加载密钥
+#[cfg(unix)]
+pub async fn reload_keys_on_hangup(
+    config_path: String,
+    forwards: Vec<(String, ForwardKeys)>,
+    mut stop_receiver: tokio::sync::broadcast::Receiver<()>,
+) -> io::Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                async_info!("Received SIGHUP, reloading keys from ",config_path);
+                reload_keys(&config_path, &forwards).await;
+            }
+            _ = stop_receiver.recv() => {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// 配置文件修改时间变化时重新加载密钥，Windows 服务等无法发送 SIGHUP 的场景也能轮换密钥
+pub async fn reload_keys_on_change(
+    config_path: String,
+    forwards: Vec<(String, ForwardKeys)>,
+    mut stop_receiver: tokio::sync::broadcast::Receiver<()>,
+) -> io::Result<()> {
+    let modified = |path: &str| std::fs::metadata(path).and_then(|meta| meta.modified()).ok();
+
+    let mut last_modified = modified(&config_path);
+    let mut ticker = tokio::time::interval(CONFIG_POLL_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticker.tick().await;
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let current = modified(&config_path);
+                if current.is_none() || current == last_modified {
+                    continue;
+                }
+                last_modified = current;
+                async_info!("Config file changed, reloading keys from ",config_path);
+                reload_keys(&config_path, &forwards).await;
+            }
+            _ = stop_receiver.recv() => {
+                return Ok(());
+            }
+        }
+    }
+}
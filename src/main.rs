@@ -1,31 +1,79 @@
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{ AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::io::AsyncReadExt;
 use tokio::fs::File;
 use tokio::task::JoinSet;
 use tokio::sync::broadcast;
+use tokio::time::{sleep, timeout};
 
 
 
 
 use std::io;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 use std::env;
 
 
 
 use serde::Deserialize;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use clap::Parser;
 use clap::Subcommand;
 
 use tklog::{
-    async_error, async_info,  LEVEL, Format, ASYNC_LOG,LOG,info
+    async_error, async_info,  LEVEL, Format, ASYNC_LOG,LOG,error
 };
 
 mod encryption;
-use encryption::{SimpleEncryptionContext, encrypt_and_prepend_length};
+use encryption::{CipherSuite, DEFAULT_PASSPHRASE_SALT, derive_key_from_passphrase, generate_key, write_key_file};
+
+mod keys;
+use keys::{ForwardKeys, KeyEntry, load_forward_keys};
+
+mod compression;
+use compression::Compression;
+
+mod padding;
+use padding::Padding;
+
+mod identity;
+use identity::{AllowedIdentities, AllowedIdentity, IdentityConfig, LocalIdentity};
+
+mod stream;
+use stream::BoxStream;
+
+mod address;
+use address::Listener;
+
+mod tls;
+use tls::{LocalTls, RemoteTls, RemoteTlsConnector};
+use tokio_rustls::TlsAcceptor;
+
+mod preamble;
+mod handshake;
 
 mod buffer;
-use buffer::PacketBuffer;
+mod frame;
+
+mod relay;
+use relay::Link;
+
+mod mux;
+use mux::{MuxPool, MuxSession, Role};
+
+mod reverse;
+use reverse::ForwardRole;
+
+mod udp;
+use udp::Protocol;
+
+mod upstream;
+use upstream::{Balance, BackendGuard, HealthCheck, Upstreams};
+
+mod resolver;
+use resolver::{AddressFamily, DEFAULT_DNS_REFRESH, DEFAULT_HAPPY_EYEBALLS_DELAY, Resolver};
 
 mod service;
 
@@ -67,6 +115,27 @@ enum Commands {
     /// Uninstall the application from service
     Uninstall {
     },
+    /// Derive a key from a passphrase and print it
+    DeriveKey {
+        /// passphrase, read from stdin when omitted
+        #[arg(long)]
+        passphrase: Option<String>,
+        /// salt, must match the forward's passphrase salt
+        #[arg(long)]
+        salt: Option<String>,
+    },
+    /// Generate a random key for config.toml or a key file
+    Genkey {
+        /// key encoding: hex or base64
+        #[arg(long, default_value="hex")]
+        format: String,
+        /// write the key to this file (mode 0600) instead of printing it
+        #[arg(short, long)]
+        output: Option<String>,
+        /// generate an Ed25519 identity keypair instead of a shared key
+        #[arg(long)]
+        keypair: bool,
+    },
 
 }
 
@@ -78,8 +147,78 @@ struct Forward {
     remote_addr: String,
     local_encryption: bool,
     remote_encryption: bool,
+    local_key: Option<String>,
+    local_key_file: Option<String>,
+    remote_key: Option<String>,
+    remote_key_file: Option<String>,
+    local_passphrase: Option<String>,
+    local_passphrase_salt: Option<String>,
+    remote_passphrase: Option<String>,
+    remote_passphrase_salt: Option<String>,
+    #[serde(default)]
+    local_keys: Vec<KeyEntry>,
+    local_active_key: Option<u8>,
+    #[serde(default)]
+    remote_keys: Vec<KeyEntry>,
+    remote_active_key: Option<u8>,
+    #[serde(default)]
+    cipher: CipherSuite,
+    local_tls: Option<LocalTls>,
+    remote_tls: Option<RemoteTls>,
+    #[serde(default)]
+    allowed_identities: Vec<AllowedIdentity>,
+    remote_identity: Option<IdentityConfig>,
+    #[serde(default)]
+    compression: Compression,
+    #[serde(default)]
+    padding: Padding,
+    keepalive: Option<f64>,
+    #[serde(default)]
+    multiplex: bool,
+    multiplex_connections: Option<usize>,
+    #[serde(default)]
+    role: ForwardRole,
+    #[serde(default)]
+    protocol: Protocol,
+    udp_idle_timeout: Option<f64>,
+    udp_max_sessions: Option<usize>,
+    unix_mode: Option<String>,
+    unix_owner: Option<String>,
+    #[serde(default)]
+    backends: Vec<String>,
+    #[serde(default)]
+    balance: Balance,
+    health_check: Option<HealthCheck>,
+    connect_timeout: Option<f64>,
+    connect_retries: Option<u32>,
+    connect_backoff: Option<f64>,
+    #[serde(default)]
+    fallbacks: Vec<String>,
+    dns_refresh: Option<f64>,
+    #[serde(default)]
+    address_family: AddressFamily,
+    happy_eyeballs_delay: Option<f64>,
+    idle_timeout: Option<f64>,
+    max_lifetime: Option<f64>,
+    handshake_timeout: Option<f64>,
+    first_byte_timeout: Option<f64>,
 }
 
+// 启动时为每个转发准备好的密钥和 TLS 配置
+#[derive(Clone)]
+struct ForwardState {
+    keys: ForwardKeys,
+    local_tls: Option<TlsAcceptor>,
+    remote_tls: Option<RemoteTlsConnector>,
+    allowed_identities: Arc<AllowedIdentities>,
+    remote_identity: Option<Arc<LocalIdentity>>,
+    // 远程加密侧的多路复用会话池
+    mux: Option<Arc<MuxPool>>,
+    // remote_addr 和 backends 组成的后端列表
+    upstreams: Arc<Upstreams>,
+    // 远程主机名的解析缓存
+    resolver: Arc<Resolver>,
+}
 
 
 #[derive(Deserialize)]
@@ -88,12 +227,76 @@ struct Config {
 }
 
 
+// 从口令派生密钥并打印
+fn derive_key_command(passphrase: Option<String>, salt: Option<String>) -> io::Result<()> {
+    let passphrase = match passphrase {
+        Some(passphrase) => passphrase,
+        None => {
+            let mut line = String::new();
+            io::stdin().read_line(&mut line)?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    let salt = salt.unwrap_or_else(|| DEFAULT_PASSPHRASE_SALT.to_string());
+
+    let key = derive_key_from_passphrase(&passphrase, &salt)?;
+    println!("{}", hex::encode(key));
+    Ok(())
+}
+
+// 生成随机密钥或身份密钥对并打印
+fn genkey_command(format: String, output: Option<String>, keypair: bool) -> io::Result<()> {
+    let key = generate_key();
+    let encode = |key: &[u8]| match format.as_str() {
+        "hex" => Ok(hex::encode(key)),
+        "base64" => Ok(BASE64.encode(key)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown key format: {}", format))),
+    };
+    let text = encode(&key)?;
+
+    // 身份密钥对：私钥配置在发起连接一端的 remote_identity，公钥加入监听一端的 allowed_identities
+    if keypair {
+        let public_key = encode(&identity::public_key_of(&key))?;
+        match output {
+            Some(path) => {
+                write_key_file(&path, &text)?;
+                println!("private_key_file = \"{}\"", path);
+            }
+            None => println!("private_key = \"{}\"", text),
+        }
+        println!("public_key = \"{}\"", public_key);
+        return Ok(());
+    }
+
+    // 发起连接的一端配置 remote_*，监听的一端配置 local_*
+    match output {
+        Some(path) => {
+            write_key_file(&path, &text)?;
+            println!("remote_key_file = \"{}\"", path);
+            println!("local_key_file = \"{}\"", path);
+        }
+        None => {
+            println!("remote_key = \"{}\"", text);
+            println!("local_key = \"{}\"", text);
+        }
+    }
+    Ok(())
+}
+
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
 
 
     let mut args = Args::parse();
 
+    // 密钥命令在初始化日志之前执行，标准输出只有密钥，可以直接重定向或用于命令替换
+    let command = match args.command.take() {
+        Some(Commands::DeriveKey { passphrase, salt }) => return derive_key_command(passphrase, salt),
+        Some(Commands::Genkey { format, output, keypair }) => return genkey_command(format, output, keypair),
+        command => command,
+    };
+
     #[cfg(target_os = "windows")]
     let default_path = Path::new("D:\\").to_path_buf();
 
@@ -122,11 +325,11 @@ async fn main() -> io::Result<()> {
     };
 
     
-    if args.config == "config.toml".to_string(){
+    if args.config == "config.toml"{
         args.config = app_path.join("config.toml").to_str().unwrap().to_string();
 
     }
-    if args.log == "PortForward.log".to_string(){
+    if args.log == "PortForward.log"{
         args.log = app_path.join("PortForward.log").to_str().unwrap().to_string();
     }
  
@@ -141,7 +344,7 @@ async fn main() -> io::Result<()> {
     async_info!("Log path is: ",args.log);
 
 
-    match args.command {
+    match command {
         Some(Commands::Install {}) => {
 
             #[cfg(target_os = "windows")]
@@ -160,6 +363,8 @@ async fn main() -> io::Result<()> {
 
             let _ = uninstall_linux();
         },
+        // 密钥命令已在初始化日志之前执行
+        Some(Commands::DeriveKey { .. } | Commands::Genkey { .. }) => {},
         None => {
 
 
@@ -187,7 +392,8 @@ async fn main() -> io::Result<()> {
 
                 if let Err(e) = start_listen(stop_sender).await{
                     
-                    async_error!(e.to_string());
+                    // 同步日志，保证进程退出前写出启动失败原因
+                    error!(e.to_string());
                 }
 
                 
@@ -199,6 +405,7 @@ async fn main() -> io::Result<()> {
 }
 
 
+#[allow(clippy::borrow_interior_mutable_const)]
 async fn async_log_init(log_path:String) {
     // Configure global singleton
 
@@ -210,6 +417,7 @@ async fn async_log_init(log_path:String) {
         .await;
 }
 
+#[allow(clippy::borrow_interior_mutable_const)]
 fn log_init(log_path:String) {
     // Configure global singleton
 
@@ -225,105 +433,460 @@ fn log_init(log_path:String) {
 
 
 
+fn load_forward_state(forward: &Forward) -> io::Result<ForwardState> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, format!("[ {} ] {}", forward.name, msg));
+
+    if forward.local_tls.is_some() && forward.local_encryption {
+        return Err(invalid("local_tls and local_encryption cannot both be enabled".to_string()));
+    }
+    if forward.remote_tls.is_some() && forward.remote_encryption {
+        return Err(invalid("remote_tls and remote_encryption cannot both be enabled".to_string()));
+    }
+    forward.padding.validate().map_err(invalid)?;
+    address::validate_unix_options(forward).map_err(invalid)?;
+    // 远程地址：remote_addr、backends 和 fallbacks，未配置 sni 时 TLS 按各自的主机名连接
+    let remote_addrs: Vec<&str> = std::iter::once(&forward.remote_addr)
+        .chain(&forward.backends)
+        .chain(&forward.fallbacks)
+        .map(String::as_str)
+        .collect();
+    if forward.remote_tls.as_ref().is_some_and(|tls| tls.sni.is_none())
+        && remote_addrs.iter().any(|addr| address::unix_path(addr).is_some())
+    {
+        return Err(invalid("remote_tls to a unix: address requires sni".to_string()));
+    }
+    if forward.keepalive.is_some_and(|seconds| !(seconds.is_finite() && seconds > 0.0)) {
+        return Err(invalid("keepalive must be a positive number of seconds".to_string()));
+    }
+
+    let local_tls = match &forward.local_tls {
+        Some(config) => Some(tls::build_acceptor(config).map_err(|e| invalid(format!("local_tls: {}", e)))?),
+        None => None,
+    };
+
+    // 允许列表只对本地加密握手或带客户端证书校验的 TLS 监听生效
+    let allowed_identities = identity::load_allowed_identities(&forward.allowed_identities)
+        .map_err(|e| invalid(format!("allowed_identities: {}", e)))?;
+    if !allowed_identities.is_empty() && !forward.local_encryption
+        && forward.local_tls.as_ref().is_none_or(|tls| tls.client_ca.is_none())
+    {
+        return Err(invalid("allowed_identities requires local_encryption or local_tls with client_ca".to_string()));
+    }
+
+    let remote_identity = match &forward.remote_identity {
+        Some(_) if !forward.remote_encryption => {
+            return Err(invalid("remote_identity requires remote_encryption".to_string()));
+        }
+        Some(config) => Some(Arc::new(
+            identity::load_identity(config).map_err(|e| invalid(format!("remote_identity: {}", e)))?,
+        )),
+        None => None,
+    };
+
+    // UDP 转发直接收发数据报，不支持 TCP 链路上的功能
+    if forward.protocol == Protocol::Udp {
+        if forward.local_encryption && forward.remote_encryption {
+            return Err(invalid("protocol udp supports local_encryption or remote_encryption, not both".to_string()));
+        }
+        if forward.local_tls.is_some() || forward.remote_tls.is_some() {
+            return Err(invalid("protocol udp does not support local_tls or remote_tls".to_string()));
+        }
+        if forward.multiplex || forward.role != ForwardRole::Forward {
+            return Err(invalid("protocol udp does not support multiplex or reverse tunnels".to_string()));
+        }
+        // 只有加密链路一侧是 TCP，可以使用 Unix 域套接字
+        if (address::unix_path(&forward.local_addr).is_some() && !forward.local_encryption)
+            || (address::unix_path(&forward.remote_addr).is_some() && !forward.remote_encryption)
+        {
+            return Err(invalid("protocol udp only supports unix: addresses on the encrypted side".to_string()));
+        }
+    }
+    if forward.udp_idle_timeout.is_some_and(|seconds| !(seconds.is_finite() && seconds > 0.0)) {
+        return Err(invalid("udp_idle_timeout must be a positive number of seconds".to_string()));
+    }
+    if forward.udp_max_sessions == Some(0) {
+        return Err(invalid("udp_max_sessions must be at least 1".to_string()));
+    }
+
+    // 公网端的 remote_addr 是隧道监听地址，UDP 会话固定连接 remote_addr
+    if !forward.backends.is_empty() && (forward.role == ForwardRole::ReverseServer || forward.protocol == Protocol::Udp) {
+        return Err(invalid("backends are not supported for reverse_server or protocol udp".to_string()));
+    }
+    if let Some(health_check) = &forward.health_check {
+        if forward.role == ForwardRole::ReverseServer || forward.protocol == Protocol::Udp {
+            return Err(invalid("health_check is not supported for reverse_server or protocol udp".to_string()));
+        }
+        health_check.validate().map_err(invalid)?;
+    }
+    let durations = [
+        ("connect_timeout", forward.connect_timeout),
+        ("connect_backoff", forward.connect_backoff),
+        ("dns_refresh", forward.dns_refresh),
+        ("happy_eyeballs_delay", forward.happy_eyeballs_delay),
+        ("idle_timeout", forward.idle_timeout),
+        ("max_lifetime", forward.max_lifetime),
+        ("handshake_timeout", forward.handshake_timeout),
+        ("first_byte_timeout", forward.first_byte_timeout),
+    ];
+    for (option, seconds) in durations {
+        if seconds.is_some_and(|seconds| !(seconds.is_finite() && seconds > 0.0)) {
+            return Err(invalid(format!("{} must be a positive number of seconds", option)));
+        }
+    }
+    // UDP 会话由 udp_idle_timeout 回收
+    if forward.protocol == Protocol::Udp
+        && (forward.idle_timeout.is_some() || forward.max_lifetime.is_some() || forward.first_byte_timeout.is_some())
+    {
+        return Err(invalid("protocol udp uses udp_idle_timeout instead of idle_timeout, max_lifetime or first_byte_timeout".to_string()));
+    }
+    if !forward.fallbacks.is_empty() && (forward.role == ForwardRole::ReverseServer || forward.protocol == Protocol::Udp) {
+        return Err(invalid("fallbacks are not supported for reverse_server or protocol udp".to_string()));
+    }
+    let upstreams = Upstreams::new(
+        std::iter::once(forward.remote_addr.clone()).chain(forward.backends.iter().cloned()).collect(),
+        forward.balance,
+    );
+
+    // 反向隧道的公网端必须加密并复用隧道，内网端必须用本地加密与公网端握手
+    match forward.role {
+        ForwardRole::ReverseServer if !forward.remote_encryption => {
+            return Err(invalid("reverse_server requires remote_encryption for the tunnel".to_string()));
+        }
+        ForwardRole::ReverseServer if forward.multiplex => {
+            return Err(invalid("reverse_server always multiplexes the tunnel, remove multiplex".to_string()));
+        }
+        ForwardRole::ReverseClient if !forward.local_encryption => {
+            return Err(invalid("reverse_client requires local_encryption for the tunnel".to_string()));
+        }
+        _ => {}
+    }
+    // 注册帧携带转发名称，必须能放进一个加密帧
+    if forward.role != ForwardRole::Forward
+        && frame::Frame::Register(forward.name.clone()).encode().len() > forward.padding.frame_capacity()
+    {
+        return Err(invalid("name is too long for the constant padding size of a reverse tunnel".to_string()));
+    }
+
+    // 内网端的流都来自同一条隧道，没有各自的客户端地址，一致性哈希会把它们全部固定到一个后端
+    if forward.role == ForwardRole::ReverseClient && forward.balance == Balance::ConsistentHash {
+        return Err(invalid("balance consistent_hash is not supported for reverse_client".to_string()));
+    }
+
+    let mux = match (forward.multiplex, forward.multiplex_connections) {
+        // 公网端的会话池由内网端连入的隧道填充
+        _ if forward.role == ForwardRole::ReverseServer => Some(Arc::new(MuxPool::new(0))),
+        (false, _) => None,
+        (true, _) if !forward.remote_encryption => {
+            return Err(invalid("multiplex requires remote_encryption".to_string()));
+        }
+        (true, Some(0)) => return Err(invalid("multiplex_connections must be at least 1".to_string())),
+        // 多个客户端的流共用会话，无法按客户端固定后端
+        (true, _) if forward.balance == Balance::ConsistentHash => {
+            return Err(invalid("balance consistent_hash is not supported with multiplex".to_string()));
+        }
+        (true, size) => Some(Arc::new(MuxPool::new(size.unwrap_or(1)))),
+    };
+
+    let remote_tls = match &forward.remote_tls {
+        Some(config) => Some(
+            tls::build_connector(config, &remote_addrs).map_err(|e| invalid(format!("remote_tls: {}", e)))?,
+        ),
+        None => None,
+    };
+
+    Ok(ForwardState {
+        keys: load_forward_keys(forward)?,
+        local_tls,
+        remote_tls,
+        allowed_identities: Arc::new(allowed_identities),
+        remote_identity,
+        mux,
+        upstreams: Arc::new(upstreams),
+        resolver: Arc::new(Resolver::new(
+            forward.name.clone(),
+            Duration::from_secs_f64(forward.dns_refresh.unwrap_or(DEFAULT_DNS_REFRESH)),
+            forward.address_family,
+            Duration::from_secs_f64(forward.happy_eyeballs_delay.unwrap_or(DEFAULT_HAPPY_EYEBALLS_DELAY)),
+        )),
+    })
+}
+
+
+
 // 使用缓冲区的版本
 async fn handle_client_buffered(
     forward: Forward,
-    mut local: TcpStream, 
+    state: ForwardState,
+    socket: BoxStream,
+    mut peer: String,
 ) -> io::Result<()> {
-    async_info!("[ ",forward.name," ] Connect remote addr:",forward.remote_addr);
-    let mut remote = TcpStream::connect(forward.remote_addr).await?;
-    
-    let ctx = if forward.local_encryption || forward.remote_encryption {
-        Some(SimpleEncryptionContext::new())
-    } else {
-        None
+    // 一致性哈希使用客户端地址，不包含后面附加的身份
+    let client = peer.clone();
+    let mut identity: Option<String> = None;
+
+    // TLS 终止：先完成 TLS 握手，之后按明文转发
+    let mut local: BoxStream = match state.local_tls.as_ref() {
+        Some(acceptor) => match with_timeout(forward.handshake_timeout, acceptor.accept(socket)).await {
+            Ok(tls) => {
+                // 配置了允许列表时按客户端证书指纹确认身份
+                if !state.allowed_identities.is_empty() {
+                    let verified = tls.get_ref().1.peer_certificates()
+                        .and_then(|certs| certs.first())
+                        .ok_or_else(|| "Client presented no certificate".to_string())
+                        .and_then(|cert| state.allowed_identities.verify_certificate(cert));
+                    match verified {
+                        Ok(name) => identity = Some(name),
+                        Err(msg) => {
+                            async_error!("[ ",forward.name," ] ",peer," rejected: ",msg);
+                            return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+                        }
+                    }
+                }
+                Box::new(tls)
+            }
+            Err(e) => {
+                async_error!("[ ",forward.name," ] ",peer," Local TLS handshake failed: ",e.to_string());
+                return Err(e);
+            }
+        },
+        None => Box::new(socket),
     };
-    
-    let (mut local_reader, mut local_writer) = local.split();
-    let (mut remote_reader, mut remote_writer) = remote.split();
-    
-    let mut local_buffer = PacketBuffer::new();
-    let mut remote_buffer = PacketBuffer::new();
-    
-    // 从本地到远程的流量处理
-    let client_to_server = async {
-        let mut read_buffer: Vec<u8> = vec![0u8; 4096];
-        loop {
-            // 读取数据到缓冲区
-            let n: usize = local_reader.read(&mut read_buffer).await?;
-            
-            if n == 0 {
-                break;
+
+    // 本地加密侧先完成握手，对端认证失败时不会连接远程
+    let mut multiplexed = false;
+    let local = match state.keys.local.as_ref() {
+        Some(keyring) => match with_timeout(
+            forward.handshake_timeout,
+            handshake::respond(&mut local, keyring, forward.cipher, forward.compression, &state.allowed_identities),
+        ).await {
+            Ok(mut session) => {
+                identity = session.peer_identity;
+                multiplexed = session.multiplexed;
+                log_compression_mismatch(&forward, &peer, "Local", session.compression).await;
+                session.sender.set_padding(forward.padding.clone());
+                Link::encrypted(local, session.sender, session.receiver, session.compression)
             }
-            
-            if forward.local_encryption {
+            Err(e) => {
+                async_error!("[ ",forward.name," ] ",peer," Local handshake failed: ",e.to_string());
+                return Err(e);
+            }
+        },
+        None => Link::plain(local),
+    };
 
-                local_buffer.push_data(&read_buffer[..n]);
+    if let Some(name) = identity {
+        peer = format!("{} ( identity {} )", peer, name);
+        async_info!("[ ",forward.name," ] ",peer," authenticated");
+    }
 
-                
-                // 处理所有完整的数据包
-                while let Some(decrypted_data)= local_buffer.try_read_packet(ctx.as_ref().unwrap())? {
-                    let processed_data: Vec<u8> = if forward.remote_encryption {
-                        encrypt_and_prepend_length(&decrypted_data, ctx.as_ref().unwrap()).await?
-                    } else {
-                        decrypted_data
-                    };
-                    
-                    remote_writer.write_all(&processed_data).await?;
-                }
-            } else {
-                // 非加密模式直接读取，远程需要加密就加密后再发
-                let processed_data: Vec<u8> = if forward.remote_encryption {
-                    encrypt_and_prepend_length(&read_buffer[..n], ctx.as_ref().unwrap()).await?
-                } else {
-                    read_buffer[..n].to_vec()
-                };
-                remote_writer.write_all(&processed_data).await?;
-            }
+    // 多路复用会话：对端打开的每个流各自连接远程
+    if multiplexed {
+        serve_multiplexed(forward, state, client, peer, local).await;
+        return Ok(());
+    }
+
+    connect_and_relay(forward, state, client, peer, local).await
+}
+
+pub(crate) async fn serve_multiplexed(forward: Forward, state: ForwardState, client: String, peer: String, link: Link) {
+    async_info!("[ ",forward.name," ] ",peer," Multiplexed session started");
+    let keepalive = forward.keepalive.map(Duration::from_secs_f64);
+    let (_, mut streams) = MuxSession::start(link, Role::Responder, keepalive, format!("[ {} ] {}", forward.name, peer), None);
+
+    while let Some((id, stream)) = streams.recv().await {
+        let stream_peer = format!("{} stream {}", peer, id);
+        tokio::spawn(connect_and_relay(forward.clone(), state.clone(), client.clone(), stream_peer, Link::stream(stream)));
+    }
+}
+
+// 连接远程并双向转发
+async fn connect_and_relay(forward: Forward, state: ForwardState, client: String, peer: String, mut local: Link) -> io::Result<()> {
+    let mut backend = None;
+    let remote = match state.mux.as_ref() {
+        Some(pool) => open_remote_stream(&forward, &state, &peer, pool).await,
+        // 后端的连接计数持续到转发结束
+        None => connect_backend(&forward, &state, &peer, &client, false).await.map(|(picked, link)| {
+            backend = picked;
+            link
+        }),
+    };
+    let mut remote = match remote {
+        Ok(remote) => remote,
+        Err(e) => {
+            // 告诉加密链路的对端连接失败的原因
+            local.writer().close(&e.to_string()).await;
+            return Err(e);
         }
-        Ok::<(), io::Error>(())
     };
-    
-    // 从远程到本地的流量处理（类似逻辑）
-    let server_to_client = async {
-        let mut read_buffer = vec![0u8; 4096];
-        loop {
-            let n: usize = remote_reader.read(&mut read_buffer).await?;
-            if n == 0 {
-                break;
+
+    // 双向转发，半关闭、保活和流控由控制帧处理；帧校验失败（重放、乱序、篡改）或对端报错时记录原因并关闭连接
+    let keepalive = forward.keepalive.map(Duration::from_secs_f64);
+    let limits = relay::Limits {
+        idle_timeout: forward.idle_timeout.map(Duration::from_secs_f64),
+        max_lifetime: forward.max_lifetime.map(Duration::from_secs_f64),
+        first_byte_timeout: forward.first_byte_timeout.map(Duration::from_secs_f64),
+    };
+    let result = relay::relay(&mut local, &mut remote, keepalive, limits).await;
+
+    // 关闭时记录每条压缩链路的压缩率
+    let summaries = [("Local", local.compression_summary()), ("Remote", remote.compression_summary())];
+    for (name, summary) in summaries {
+        if let Some(summary) = summary {
+            async_info!("[ ",forward.name," ] ",peer," ",name," ",summary);
+        }
+    }
+
+    if let Err(e) = result {
+        async_error!("[ ",forward.name," ] ",peer," Connection closed: ",e.to_string());
+        return Err(e);
+    }
+    async_info!("[ ",forward.name," ] ",peer," Connection finished");
+    Ok(())
+}
+
+// 连接重试的初始等待时间，每次重试翻倍，不超过上限
+const DEFAULT_CONNECT_BACKOFF: f64 = 0.5;
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(10);
+
+// 按均衡策略选择后端并连接，返回选中的后端；所有后端都被健康检查判定下线时直接尝试 fallbacks
+async fn connect_backend(forward: &Forward, state: &ForwardState, peer: &str, client: &str, multiplex: bool) -> io::Result<(Option<BackendGuard>, Link)> {
+    match state.upstreams.pick(client) {
+        Ok(backend) => {
+            let link = connect_with_retry(forward, state, peer, Some(backend.addr()), multiplex).await?;
+            Ok((Some(backend), link))
+        }
+        Err(e) if !forward.fallbacks.is_empty() => {
+            async_info!("[ ",forward.name," ] ",peer," ",e.to_string(),", trying fallbacks");
+            Ok((None, connect_with_retry(forward, state, peer, None, multiplex).await?))
+        }
+        Err(e) => {
+            async_error!("[ ",forward.name," ] ",peer," ",e.to_string());
+            Err(e)
+        }
+    }
+}
+
+// 连接远程地址，失败时按 connect_retries 指数退避重试，仍然失败再按顺序尝试 fallbacks；
+// addr 为 None 时只尝试 fallbacks。客户端连接在重试期间保持等待，上游短暂重启不会让客户端被重置
+pub(crate) async fn connect_with_retry(forward: &Forward, state: &ForwardState, peer: &str, addr: Option<&str>, multiplex: bool) -> io::Result<Link> {
+    let retries = forward.connect_retries.unwrap_or(0);
+    let mut last_error = io::Error::new(io::ErrorKind::NotConnected, "No remote address");
+
+    let candidates = addr.into_iter().map(|addr| (addr, false)).chain(forward.fallbacks.iter().map(|addr| (addr.as_str(), true)));
+    for (candidate, fallback) in candidates {
+        if fallback {
+            async_info!("[ ",forward.name," ] ",peer," Falling back to ",candidate);
+        }
+        let mut backoff = Duration::from_secs_f64(forward.connect_backoff.unwrap_or(DEFAULT_CONNECT_BACKOFF));
+        for attempt in 0..=retries {
+            if attempt > 0 {
+                async_info!("[ ",forward.name," ] ",peer," Retry ",candidate," in ",format!("{:.1}s", backoff.as_secs_f64())," (",attempt,"/",retries,")");
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
             }
-            
-            if forward.remote_encryption {
-                remote_buffer.push_data(&read_buffer[..n]);
-                
-                while let Some(decrypted_data) = remote_buffer.try_read_packet(ctx.as_ref().unwrap())? {
-                    let processed_data = if forward.local_encryption {
-                        encrypt_and_prepend_length(&decrypted_data, ctx.as_ref().unwrap()).await?
-                    } else {
-                        decrypted_data
-                    };
-                    
-                    local_writer.write_all(&processed_data).await?;
-                }
-            } else {
+            match connect_remote(forward, state, peer, candidate, multiplex).await {
+                Ok(link) => return Ok(link),
+                Err(e) => last_error = e,
+            }
+        }
+    }
+    Err(last_error)
+}
 
-                let processed_data = if forward.local_encryption {
-                    encrypt_and_prepend_length(&read_buffer[..n], ctx.as_ref().unwrap()).await?
-                } else {
-                    read_buffer[..n].to_vec()
-                };
-                local_writer.write_all(&processed_data).await?;
+// 连接远程地址 addr，按配置完成 TLS 或加密握手；失败时记录日志并返回带原因的错误
+pub(crate) async fn connect_remote(forward: &Forward, state: &ForwardState, peer: &str, addr: &str, multiplex: bool) -> io::Result<Link> {
+    async_info!("[ ",forward.name," ] ",peer," Connect remote addr:",addr);
+    match open_remote(forward, state, addr, multiplex).await {
+        Ok(link) => {
+            if let Some(compression) = link.compression() {
+                log_compression_mismatch(forward, peer, "Remote", compression).await;
             }
+            Ok(link)
         }
-        Ok(())
+        Err(e) => {
+            async_error!("[ ",forward.name," ] ",peer," ",e.to_string());
+            Err(e)
+        }
+    }
+}
+
+// 建立到 addr 的远程链路，不记录日志；健康检查也经由这里，和转发的连接走同样的 TLS 和加密握手
+pub(crate) async fn open_remote(forward: &Forward, state: &ForwardState, addr: &str, multiplex: bool) -> io::Result<Link> {
+    let remote_socket = with_timeout(forward.connect_timeout, address::connect(addr, &state.resolver)).await
+        .map_err(|e| io::Error::new(e.kind(), format!("Connect remote addr {} failed: {}", addr, e)))?;
+
+    // TLS 发起：与远程目标建立 TLS 会话后按明文读写，服务器名按本次连接的地址确定
+    let mut remote: BoxStream = match state.remote_tls.as_ref() {
+        Some(tls) => {
+            let connected = match tls.server_name(addr) {
+                Ok(server_name) => with_timeout(forward.handshake_timeout, tls.connector.connect(server_name, remote_socket)).await,
+                Err(e) => Err(e),
+            };
+            let tls = connected.map_err(|e| io::Error::new(e.kind(), format!("Remote TLS handshake failed: {}", e)))?;
+            Box::new(tls)
+        }
+        None => remote_socket,
     };
-    
-    tokio::try_join!(client_to_server, server_to_client)?;
-    Ok(())
+
+    match state.keys.remote.as_ref() {
+        Some(keyring) => {
+            let mut session = with_timeout(
+                forward.handshake_timeout,
+                handshake::initiate(&mut remote, keyring, forward.cipher, forward.compression, multiplex, state.remote_identity.as_deref()),
+            ).await
+            .map_err(|e| io::Error::new(e.kind(), format!("Remote handshake failed: {}", e)))?;
+            session.sender.set_padding(forward.padding.clone());
+            Ok(Link::encrypted(remote, session.sender, session.receiver, session.compression))
+        }
+        None => Ok(Link::plain(remote)),
+    }
+}
+
+// 限制连接或握手的时间，seconds 为 None 时不限制
+pub(crate) async fn with_timeout<T>(seconds: Option<f64>, future: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    match seconds {
+        Some(seconds) => timeout(Duration::from_secs_f64(seconds), future).await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, format!("timed out after {}s", seconds)))),
+        None => future.await,
+    }
 }
 
+// 在多路复用会话上打开一个流，会话不足时新建
+async fn open_remote_stream(forward: &Forward, state: &ForwardState, peer: &str, pool: &MuxPool) -> io::Result<Link> {
+    // 反向隧道只能使用内网端已经建立的隧道
+    if forward.role == ForwardRole::ReverseServer {
+        let Some(session) = pool.pick() else {
+            async_error!("[ ",forward.name," ] ",peer," No reverse tunnel registered");
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "No reverse tunnel registered"));
+        };
+        let (id, stream) = session.open()?;
+        async_info!("[ ",forward.name," ] ",peer," Open stream ",id," on reverse tunnel");
+        return Ok(Link::stream(stream));
+    }
 
-async  fn listening(listener: TcpListener, forward :Forward, mut  stop_receiver:  tokio::sync::broadcast::Receiver<()>)  -> io::Result<()>{
+    let session = pool.session(|| async {
+        let (backend, link) = connect_backend(forward, state, "multiplexed session", &forward.name, true).await?;
+        let keepalive = forward.keepalive.map(Duration::from_secs_f64);
+        let name = format!("[ {} ] {}", forward.name, backend.as_ref().map_or("fallbacks", |backend| backend.addr()));
+        async_info!(name.as_str()," Multiplexed session started");
+        // 后端的连接计数持续到会话结束
+        Ok(MuxSession::start(link, Role::Initiator, keepalive, name, backend).0)
+    }).await?;
+
+    let (id, stream) = session.open()?;
+    async_info!("[ ",forward.name," ] ",peer," Open stream ",id," on multiplexed session");
+    Ok(Link::stream(stream))
+}
+
+// 两端压缩配置不一致时不压缩
+pub(crate) async fn log_compression_mismatch(forward: &Forward, peer: &str, link: &str, agreed: Compression) {
+    if agreed != forward.compression {
+        async_info!("[ ",forward.name," ] ",peer," ",link," peer does not use compression ",forward.compression.name(),", sending uncompressed");
+    }
+}
+
+
+async  fn listening(listener: Listener, forward :Forward, state: ForwardState, mut  stop_receiver:  tokio::sync::broadcast::Receiver<()>)  -> io::Result<()>{
 
     loop {
 
@@ -333,10 +896,10 @@ async  fn listening(listener: TcpListener, forward :Forward, mut  stop_receiver:
             // normal work
             _ = async {
                 
-                let (socket, addr) = listener.accept().await?;
-                async_info!( "[ ",fw.name," ] receive connection from ",addr.ip().to_string());
+                let (socket, peer) = listener.accept().await?;
+                async_info!( "[ ",fw.name," ] receive connection from ",peer);
                 //tokio::spawn(handle_client(socket, remote));
-                tokio::spawn(handle_client_buffered(fw, socket));
+                tokio::spawn(handle_client_buffered(fw, state.clone(), socket, peer));
                 Ok::<(), std::io::Error>(()) 
                 
             } =>{},
@@ -357,7 +920,7 @@ pub async  fn start_listen(stop_sender:tokio::sync::broadcast::Sender<()>) ->io:
     // read config
     let args = Args::parse();
     async_info!("Start reading confg file");
-    let mut config_content = File::open(args.config).await?;
+    let mut config_content = File::open(&args.config).await?;
     let mut content_string = String::from("");
     let _ = config_content.read_to_string(&mut content_string).await?;
     
@@ -369,18 +932,64 @@ pub async  fn start_listen(stop_sender:tokio::sync::broadcast::Sender<()>) ->io:
 
         
 
+            // 启动前检查所有转发的密钥和证书，加密转发缺少密钥时拒绝启动
+            let mut forward_states = Vec::with_capacity(config.forwards.len());
+            for forward in &config.forwards {
+                forward_states.push(load_forward_state(forward)?);
+            }
+
             let mut set = JoinSet::new();
-            for forward in config.forwards {
 
-                async_info!("[ ",forward.name," ] from ",forward.local_addr," to ",forward.remote_addr," local encryption ",forward.local_encryption," remote encryption ",forward.remote_encryption);
+            // 配置文件变化或 Unix 下收到 SIGHUP 时重新加载密钥，用于不中断连接的密钥轮换
+            let reloadable: Vec<_> = config.forwards.iter()
+                .map(|forward| forward.name.clone())
+                .zip(forward_states.iter().map(|state| state.keys.clone()))
+                .collect();
+            #[cfg(unix)]
+            set.spawn(keys::reload_keys_on_hangup(args.config.clone(), reloadable.clone(), stop_sender.subscribe()));
+            set.spawn(keys::reload_keys_on_change(args.config.clone(), reloadable, stop_sender.subscribe()));
+
+            for (forward, state) in config.forwards.into_iter().zip(forward_states) {
+
+                async_info!("[ ",forward.name," ] from ",forward.local_addr," to ",forward.remote_addr," local encryption ",forward.local_encryption," remote encryption ",forward.remote_encryption," cipher ",forward.cipher.name()," compression ",forward.compression.name()," padding ",forward.padding.name()," multiplex ",forward.multiplex," role ",forward.role.name()," protocol ",forward.protocol.name());
+                if state.upstreams.len() > 1 {
+                    async_info!("[ ",forward.name," ] balance ",forward.balance.name()," across ",state.upstreams.addrs().join(", "));
+                }
+                if let Some(health_check) = &forward.health_check {
+                    set.spawn(upstream::health_checks(forward.clone(), state.clone(), health_check.clone(), stop_sender.subscribe()));
+                }
                 
                 let fw = forward.clone();
 
-                let listener: TcpListener = TcpListener::bind(forward.local_addr).await?;
+                // UDP over 加密链路的接收端在 TCP 上监听
+                if forward.protocol == Protocol::Udp && forward.local_encryption {
+                    let listener = Listener::bind(&forward.local_addr, &forward).await?;
+                    set.spawn(udp::udp_tunnel_listening(listener, fw, state, stop_sender.subscribe()));
+                    continue;
+                }
+                if forward.protocol == Protocol::Udp {
+                    let socket = UdpSocket::bind(&forward.local_addr).await?;
+                    set.spawn(udp::udp_listening(socket, fw, state, stop_sender.subscribe()));
+                    continue;
+                }
+
+                // 内网端不监听，主动连接公网端
+                if forward.role == ForwardRole::ReverseClient {
+                    set.spawn(reverse::reverse_dial(fw, state, stop_sender.subscribe()));
+                    continue;
+                }
+
+                // 公网端额外监听 remote_addr 等待内网端的隧道
+                if forward.role == ForwardRole::ReverseServer {
+                    let tunnel_listener = Listener::bind(&forward.remote_addr, &forward).await?;
+                    set.spawn(reverse::tunnel_listening(tunnel_listener, fw.clone(), state.clone(), stop_sender.subscribe()));
+                }
+
+                let listener = Listener::bind(&forward.local_addr, &forward).await?;
 
                 let  stop_reveiver =  stop_sender.subscribe();
                 
-                set.spawn(listening(listener,fw, stop_reveiver));
+                set.spawn(listening(listener,fw, state, stop_reveiver));
                 
             }
             set.join_all().await;
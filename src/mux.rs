@@ -0,0 +1,432 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::sync::{Notify, Semaphore, mpsc, oneshot};
+use tokio::time::Instant;
+
+use tklog::{async_error, async_info};
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::frame::Frame;
+use crate::relay::{Keepalive, Link, LinkReader, LinkWriter, READ_BUFFER_SIZE, add_credit};
+use crate::upstream::BackendGuard;
+
+// 多路复用：在一条长期存在的加密连接上承载多个逻辑流，每个流有独立的流控窗口。
+// 每个流在本地表现为一对 DuplexStream，一端交给转发逻辑当作普通连接使用，
+// 另一端由流任务与会话之间搬运数据。流可以带原因复位，原因以流上的 Close 帧发给对端。
+
+// 每个流每个方向的流控窗口
+const STREAM_WINDOW: usize = 256 * 1024;
+const STREAM_WINDOW_UPDATE_THRESHOLD: usize = STREAM_WINDOW / 4;
+// 流在本地的缓冲区大小
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+// 发起方使用奇数流 ID，响应方使用偶数，双方都可以打开流
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+// 交给转发逻辑的流：本地一端的 DuplexStream 和复位通道
+pub struct MuxStream {
+    pub stream: DuplexStream,
+    // 本端复位：流任务把原因发给对端后结束
+    pub reset: oneshot::Sender<String>,
+    // 对端复位的原因，流任务结束前写入，本端读到结尾时作为错误返回
+    pub peer_reset: Arc<Mutex<Option<String>>>,
+}
+
+struct StreamEntry {
+    inbound: mpsc::UnboundedSender<Frame>,
+    credit: Arc<Semaphore>,
+}
+
+struct Shared {
+    // 日志前缀，例如 "[ A ] 127.0.0.1:9001"
+    name: String,
+    role: Role,
+    outbound: mpsc::UnboundedSender<Frame>,
+    streams: Mutex<HashMap<u32, StreamEntry>>,
+    next_id: AtomicU32,
+    closed: AtomicBool,
+    last_seen: Mutex<Instant>,
+}
+
+impl Shared {
+    fn streams(&self) -> std::sync::MutexGuard<'_, HashMap<u32, StreamEntry>> {
+        self.streams.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // 流 ID 用尽的会话在没有流时关闭
+    fn close_if_idle(&self) {
+        if self.closed.load(Ordering::Relaxed) && self.streams().is_empty() {
+            let _ = self.outbound.send(Frame::Close("Stream ids exhausted".to_string()));
+        }
+    }
+
+    // 注册一个流并启动流任务，返回交给转发逻辑的一端
+    fn register(self: &Arc<Self>, id: u32) -> MuxStream {
+        let (local, bridge) = tokio::io::duplex(STREAM_BUFFER_SIZE);
+        let (inbound, inbound_receiver) = mpsc::unbounded_channel();
+        let (reset, reset_receiver) = oneshot::channel();
+        let peer_reset = Arc::new(Mutex::new(None));
+        let credit = Arc::new(Semaphore::new(STREAM_WINDOW));
+        self.streams().insert(id, StreamEntry { inbound, credit: credit.clone() });
+        let channels = StreamChannels { inbound: inbound_receiver, reset: reset_receiver, peer_reset: peer_reset.clone() };
+        tokio::spawn(run_stream(self.clone(), id, bridge, channels, credit));
+        MuxStream { stream: local, reset, peer_reset }
+    }
+}
+
+#[derive(Clone)]
+pub struct MuxSession {
+    shared: Arc<Shared>,
+}
+
+impl MuxSession {
+    // 启动会话，返回会话句柄和对端打开的流；backend 是会话连接的后端，会话结束时释放其连接计数
+    pub fn start(
+        link: Link,
+        role: Role,
+        keepalive: Option<Duration>,
+        name: String,
+        backend: Option<BackendGuard>,
+    ) -> (MuxSession, mpsc::UnboundedReceiver<(u32, MuxStream)>) {
+        let (outbound, outbound_receiver) = mpsc::unbounded_channel();
+        let (accepted, accepted_receiver) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            name,
+            role,
+            outbound,
+            streams: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(if role == Role::Initiator { 1 } else { 2 }),
+            closed: AtomicBool::new(false),
+            last_seen: Mutex::new(Instant::now()),
+        });
+
+        tokio::spawn(run_session(shared.clone(), link, outbound_receiver, accepted, keepalive, backend));
+        (MuxSession { shared }, accepted_receiver)
+    }
+
+    // 打开一个新的流
+    pub fn open(&self) -> io::Result<(u32, MuxStream)> {
+        if self.is_closed() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "Multiplexed session is closed"));
+        }
+
+        // 流 ID 用尽时不再打开新流，会话退出会话池，最后一个流结束后关闭
+        let Ok(id) = self.shared.next_id.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |id| id.checked_add(2)) else {
+            self.shared.closed.store(true, Ordering::Relaxed);
+            self.shared.close_if_idle();
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "Multiplexed session has run out of stream ids"));
+        };
+        // 先登记流再发送 Open，对端随后发来的帧都能找到这个流；Open 在数据之前发出
+        let stream = self.shared.register(id);
+        if self.shared.outbound.send(Frame::Stream(id, Box::new(Frame::Open))).is_err() {
+            self.shared.streams().remove(&id);
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "Multiplexed session is closed"));
+        }
+        Ok((id, stream))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Relaxed)
+    }
+
+    pub fn stream_count(&self) -> usize {
+        self.shared.streams().len()
+    }
+}
+
+// 会话任务：读写两个方向互不阻塞，任一方向结束即关闭会话
+async fn run_session(
+    shared: Arc<Shared>,
+    link: Link,
+    outbound: mpsc::UnboundedReceiver<Frame>,
+    accepted: mpsc::UnboundedSender<(u32, MuxStream)>,
+    keepalive: Option<Duration>,
+    backend: Option<BackendGuard>,
+) {
+    let (mut reader, mut writer) = link.split();
+
+    let result = tokio::select! {
+        result = read_session(&shared, &mut reader, accepted) => result,
+        result = write_session(&shared, &mut writer, outbound, keepalive) => result,
+    };
+
+    shared.closed.store(true, Ordering::Relaxed);
+    drop(backend);
+    // 丢弃所有流的接收通道，流任务会以会话关闭为由结束
+    let streams = std::mem::take(&mut *shared.streams());
+    match result {
+        Ok(()) => {
+            let _ = writer.shutdown().await;
+            async_info!(shared.name.as_str()," Multiplexed session finished, open streams ",streams.len());
+        }
+        Err(e) => {
+            writer.close(&e.to_string()).await;
+            async_error!(shared.name.as_str()," Multiplexed session closed: ",e.to_string()," open streams ",streams.len());
+        }
+    }
+}
+
+async fn read_session(
+    shared: &Arc<Shared>,
+    reader: &mut LinkReader,
+    accepted: mpsc::UnboundedSender<(u32, MuxStream)>,
+) -> io::Result<()> {
+    while let Some(frame) = reader.read_frame().await? {
+        *shared.last_seen.lock().unwrap_or_else(PoisonError::into_inner) = Instant::now();
+
+        match frame {
+            Frame::Stream(id, frame) => match *frame {
+                Frame::Open => {
+                    // 对端只能使用自己一侧的流 ID
+                    let peer_parity = if shared.role == Role::Initiator { 0 } else { 1 };
+                    if id % 2 != peer_parity || shared.streams().contains_key(&id) {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid stream id {} from peer", id)));
+                    }
+                    let stream = shared.register(id);
+                    if accepted.send((id, stream)).is_err() {
+                        // 本端不接受对端打开的流
+                        if let Some(entry) = shared.streams().remove(&id) {
+                            let _ = entry.inbound.send(Frame::Close("Peer does not accept streams".to_string()));
+                        }
+                    }
+                }
+                Frame::WindowUpdate(increment) => {
+                    if let Some(entry) = shared.streams().get(&id) {
+                        add_credit(&entry.credit, increment, STREAM_WINDOW)?;
+                    }
+                }
+                frame => {
+                    // 已经结束的流可能还会收到少量帧，直接丢弃
+                    if let Some(entry) = shared.streams().get(&id) {
+                        let _ = entry.inbound.send(frame);
+                    }
+                }
+            },
+            Frame::Ping(id) => {
+                let _ = shared.outbound.send(Frame::Pong(id));
+            }
+            Frame::Pong(_) => {}
+            Frame::Close(reason) => {
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, format!("Peer closed the session: {}", reason)));
+            }
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected frame on a multiplexed session"));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn write_session(
+    shared: &Arc<Shared>,
+    writer: &mut LinkWriter,
+    mut outbound: mpsc::UnboundedReceiver<Frame>,
+    keepalive: Option<Duration>,
+) -> io::Result<()> {
+    let mut keepalive = Keepalive::new(keepalive);
+
+    loop {
+        tokio::select! {
+            frame = outbound.recv() => match frame {
+                Some(frame) => writer.send(frame).await?,
+                None => return Ok(()),
+            },
+            ping = keepalive.tick(&shared.last_seen) => {
+                writer.send(ping?).await?;
+            }
+        }
+    }
+}
+
+// 流任务收到的消息：对端发来的帧、本端的复位，以及记录对端复位原因的位置
+struct StreamChannels {
+    inbound: mpsc::UnboundedReceiver<Frame>,
+    reset: oneshot::Receiver<String>,
+    peer_reset: Arc<Mutex<Option<String>>>,
+}
+
+// 流任务：在本地 DuplexStream 和会话之间搬运一个流的数据
+async fn run_stream(
+    shared: Arc<Shared>,
+    id: u32,
+    bridge: DuplexStream,
+    channels: StreamChannels,
+    credit: Arc<Semaphore>,
+) {
+    let StreamChannels { mut inbound, reset, peer_reset } = channels;
+    let (mut bridge_reader, mut bridge_writer) = tokio::io::split(bridge);
+    let send = |frame: Frame| {
+        shared.outbound.send(Frame::Stream(id, Box::new(frame)))
+            .map_err(|_e| io::Error::new(io::ErrorKind::NotConnected, "Multiplexed session is closed"))
+    };
+    // 本地方向发完 EOF 后通知对端方向，两个方向都结束时流才结束
+    let upstream_finished = Notify::new();
+
+    // 本地 -> 对端：需要对端给的额度
+    let upstream = async {
+        let mut read_buffer = vec![0u8; READ_BUFFER_SIZE];
+        loop {
+            let n = bridge_reader.read(&mut read_buffer).await?;
+            if n == 0 {
+                send(Frame::Eof)?;
+                upstream_finished.notify_one();
+                return Ok(());
+            }
+            credit.acquire_many(n as u32).await.map_err(io::Error::other)?.forget();
+            send(Frame::Data(read_buffer[..n].to_vec()))?;
+        }
+    };
+
+    // 对端 -> 本地：写入本地后再给对端窗口更新；收到 EOF 后继续等待对端的 Close，
+    // 对端复位时本地方向不会因为等不到额度而一直挂起
+    let downstream = async {
+        let mut unacknowledged = 0usize;
+        let mut acknowledged = 0usize;
+        let mut eof_received = false;
+        loop {
+            let frame = tokio::select! {
+                frame = inbound.recv() => frame,
+                _ = upstream_finished.notified(), if eof_received => return Ok(()),
+            };
+            let Some(frame) = frame else {
+                return Err(io::Error::new(io::ErrorKind::NotConnected, "Multiplexed session is closed"));
+            };
+            match frame {
+                Frame::Data(data) if !eof_received => {
+                    unacknowledged += data.len();
+                    if unacknowledged > STREAM_WINDOW {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Peer exceeded the stream flow control window"));
+                    }
+                    bridge_writer.write_all(&data).await?;
+                    acknowledged += data.len();
+                    if acknowledged >= STREAM_WINDOW_UPDATE_THRESHOLD {
+                        send(Frame::WindowUpdate(acknowledged as u32))?;
+                        unacknowledged -= acknowledged;
+                        acknowledged = 0;
+                    }
+                }
+                Frame::Eof if !eof_received => {
+                    bridge_writer.shutdown().await?;
+                    eof_received = true;
+                }
+                Frame::Close(reason) => {
+                    let e = io::Error::new(io::ErrorKind::ConnectionAborted, format!("Stream reset by peer: {}", reason));
+                    *peer_reset.lock().unwrap_or_else(PoisonError::into_inner) = Some(reason);
+                    return Err(e);
+                }
+                _ => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected frame on a stream"));
+                }
+            }
+        }
+    };
+
+    let result = tokio::select! {
+        result = async { tokio::try_join!(upstream, downstream) } => result.map(|_| ()),
+        // 本端复位：原因已由转发逻辑记录，这里只发给对端
+        Ok(reason) = reset => send(Frame::Close(reason)),
+    };
+    // 对端复位的原因由转发逻辑读写这个流时报告并记录，也不再回送 Close
+    if let Err(e) = result
+        && peer_reset.lock().unwrap_or_else(PoisonError::into_inner).is_none()
+    {
+        let _ = send(Frame::Close(e.to_string()));
+        async_error!(shared.name.as_str()," stream ",id," closed: ",e.to_string());
+    }
+    shared.streams().remove(&id);
+    shared.close_if_idle();
+}
+
+// 发起方的会话池：会话数不足时新建，否则选择流最少的会话；
+// 反向隧道的公网端由内网端连入的会话填充
+pub struct MuxPool {
+    size: usize,
+    state: Mutex<PoolState>,
+    // 有会话建立完成或连接失败时唤醒等待的调用方
+    changed: Notify,
+}
+
+struct PoolState {
+    sessions: Vec<MuxSession>,
+    // 正在建立连接的会话数，占用池中的位置
+    connecting: usize,
+}
+
+// 占用一个正在建立连接的位置，连接结束（包括调用方被取消）时释放
+struct ConnectingSlot<'a> {
+    pool: &'a MuxPool,
+}
+
+impl Drop for ConnectingSlot<'_> {
+    fn drop(&mut self) {
+        self.pool.state().connecting -= 1;
+        self.pool.changed.notify_waiters();
+    }
+}
+
+impl MuxPool {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            state: Mutex::new(PoolState { sessions: Vec::new(), connecting: 0 }),
+            changed: Notify::new(),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, PoolState> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.sessions.retain(|session| !session.is_closed());
+        state
+    }
+
+    pub async fn session<F, Fut>(&self, connect: F) -> io::Result<MuxSession>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = io::Result<MuxSession>>,
+    {
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            // 先登记等待再检查状态，检查之后的唤醒不会丢失
+            changed.as_mut().enable();
+            {
+                let mut state = self.state();
+                // 池未满时占用一个位置新建会话，连接期间不持锁，重试和退避不会阻塞其他客户端
+                if state.sessions.len() + state.connecting < self.size {
+                    state.connecting += 1;
+                    break;
+                }
+                if let Some(session) = state.sessions.iter().min_by_key(|session| session.stream_count()) {
+                    return Ok(session.clone());
+                }
+            }
+            // 所有位置都在建立连接，等其中一个完成
+            changed.await;
+        }
+
+        let slot = ConnectingSlot { pool: self };
+        let session = connect().await?;
+        self.state().sessions.push(session.clone());
+        drop(slot);
+        Ok(session)
+    }
+
+    // 加入一个由对端建立的会话
+    pub fn add(&self, session: MuxSession) {
+        self.state().sessions.push(session);
+    }
+
+    // 选择流最少的会话，没有可用会话时返回 None
+    pub fn pick(&self) -> Option<MuxSession> {
+        self.state().sessions.iter().min_by_key(|session| session.stream_count()).cloned()
+    }
+}
@@ -0,0 +1,128 @@
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use serde::Deserialize;
+use std::io;
+
+// 单帧明文上限：密文（明文 + 16 字节认证标签）不能超过 PacketBuffer 的最大包长
+pub const MAX_FRAME_PLAINTEXT: usize = 65536 - 16;
+
+// 填充标记：数据后接 0x80，其余补 0，接收方去掉末尾的 0 和标记即可还原
+const PADDING_MARKER: u8 = 0x80;
+
+// 单帧可承载的数据上限，帧明文还要加一个填充标记字节
+pub const MAX_FRAME_DATA: usize = MAX_FRAME_PLAINTEXT - 1;
+
+// 固定帧长的下限，每帧至少要放得下帧编码和压缩标记
+const MIN_CONSTANT_SIZE: usize = 64;
+
+// 加密帧的填充策略，大小均指每帧承载的数据长度（线上每帧再加固定的帧头、填充标记和认证标签）
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(tag = "mode", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Padding {
+    #[default]
+    None,
+    // 补齐到不小于数据长度的最小档位，超过最大档位时补齐到最大档位的整数倍
+    Buckets {
+        #[serde(default = "default_buckets")]
+        sizes: Vec<usize>,
+    },
+    // 追加 0 到 max 字节的随机填充
+    Random {
+        #[serde(default = "default_random_max")]
+        max: usize,
+    },
+    // 每帧都是固定大小，较大的数据拆成多帧
+    Constant {
+        #[serde(default = "default_constant_size")]
+        size: usize,
+    },
+}
+
+fn default_buckets() -> Vec<usize> {
+    vec![256, 1024, 4096, 16384]
+}
+
+fn default_random_max() -> usize {
+    255
+}
+
+fn default_constant_size() -> usize {
+    1024
+}
+
+impl Padding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Padding::None => "none",
+            Padding::Buckets { .. } => "buckets",
+            Padding::Random { .. } => "random",
+            Padding::Constant { .. } => "constant",
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let in_range = |size: usize| (1..=MAX_FRAME_DATA).contains(&size);
+        match self {
+            Padding::None => Ok(()),
+            Padding::Buckets { sizes } if sizes.is_empty() => Err("padding sizes must not be empty".to_string()),
+            Padding::Buckets { sizes } if !sizes.iter().all(|size| in_range(*size)) => {
+                Err(format!("padding sizes must be between 1 and {}", MAX_FRAME_DATA))
+            }
+            Padding::Random { max } if *max > MAX_FRAME_DATA => {
+                Err(format!("padding max must not exceed {}", MAX_FRAME_DATA))
+            }
+            Padding::Constant { size } if !(MIN_CONSTANT_SIZE..=MAX_FRAME_DATA).contains(size) => {
+                Err(format!("padding size must be between {} and {}", MIN_CONSTANT_SIZE, MAX_FRAME_DATA))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // 单帧可承载的数据长度，固定帧长模式下为配置的帧长
+    pub fn frame_capacity(&self) -> usize {
+        match self {
+            Padding::Constant { size } => *size,
+            _ => MAX_FRAME_DATA,
+        }
+    }
+
+    // 把一个编码后的帧填充成一帧明文；帧被拆到多个加密帧后接收方无法解码，超过单帧容量时返回错误
+    pub fn pad(&self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        if chunk.len() > self.frame_capacity() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Frame of {} bytes exceeds the frame capacity of {} bytes", chunk.len(), self.frame_capacity()),
+            ));
+        }
+
+        let len = chunk.len();
+        let data_len = match self {
+            Padding::None => len,
+            Padding::Buckets { sizes } => match sizes.iter().copied().filter(|size| *size >= len).min() {
+                Some(size) => size,
+                None => {
+                    let largest = sizes.iter().copied().max().unwrap_or(len);
+                    len.div_ceil(largest) * largest
+                }
+            },
+            Padding::Random { max } => len + (OsRng.next_u32() as usize) % (max + 1),
+            Padding::Constant { size } => *size,
+        }
+        .min(MAX_FRAME_DATA);
+        let target = data_len + 1;
+
+        let mut frame = Vec::with_capacity(target);
+        frame.extend_from_slice(chunk);
+        frame.push(PADDING_MARKER);
+        frame.resize(target, 0);
+        Ok(frame)
+    }
+}
+
+// 去掉帧明文末尾的填充
+pub fn unpad(mut frame: Vec<u8>) -> io::Result<Vec<u8>> {
+    let marker = frame.iter().rposition(|byte| *byte != 0)
+        .filter(|position| frame[*position] == PADDING_MARKER)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid frame padding"))?;
+    frame.truncate(marker);
+    Ok(frame)
+}
@@ -0,0 +1,112 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use std::io;
+
+// 加密链路的连接前导：魔数 | 最低版本 | 最高版本 | 功能标志
+// 握手前由发起方先发送，响应方校验后回复自己的前导，两端据此协商版本和共同支持的功能；
+// 前导内容计入握手记录，防止被中间人降级。
+
+const MAGIC: &[u8; 4] = b"PFWD";
+pub const PREAMBLE_LEN: usize = MAGIC.len() + 2 + 2;
+
+// 本端支持的协议版本范围，帧格式或握手不兼容的改动需要提升版本
+// 版本 2：帧明文带帧类型，支持控制帧
+const MIN_VERSION: u8 = 2;
+const MAX_VERSION: u8 = 2;
+
+// 功能标志：对端未声明的功能不会启用
+pub const FEATURE_COMPRESSION: u16 = 1 << 0;
+pub const FEATURE_MULTIPLEX: u16 = 1 << 1;
+
+const SUPPORTED_FEATURES: u16 = FEATURE_COMPRESSION | FEATURE_MULTIPLEX;
+
+// 协商结果，当前只有一个协议版本，版本号只用于兼容性检查
+#[derive(Clone, Copy, Debug)]
+pub struct Negotiated {
+    pub features: u16,
+    // 双方原始前导，用于握手记录
+    pub transcript: [u8; PREAMBLE_LEN * 2],
+}
+
+impl Negotiated {
+    pub fn supports(&self, feature: u16) -> bool {
+        self.features & feature != 0
+    }
+}
+
+fn encode() -> [u8; PREAMBLE_LEN] {
+    let mut preamble = [0u8; PREAMBLE_LEN];
+    preamble[..4].copy_from_slice(MAGIC);
+    preamble[4] = MIN_VERSION;
+    preamble[5] = MAX_VERSION;
+    preamble[6..].copy_from_slice(&SUPPORTED_FEATURES.to_be_bytes());
+    preamble
+}
+
+// 作为发起方交换前导
+pub async fn initiate<S>(stream: &mut S) -> io::Result<Negotiated>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let local = encode();
+    stream.write_all(&local).await?;
+
+    let mut peer = [0u8; PREAMBLE_LEN];
+    stream.read_exact(&mut peer).await
+        .map_err(|e| io::Error::new(e.kind(), format!("Peer closed before protocol preamble: {}", e)))?;
+    if &peer[..4] != MAGIC {
+        return Err(protocol_error(
+            "Peer did not answer with a PortForward preamble, check that local_encryption is enabled on the peer".to_string(),
+        ));
+    }
+
+    negotiate(&local, &peer, &local, &peer)
+}
+
+// 作为响应方交换前导；魔数不对时不回复，版本不兼容时先回复再断开，让对端也能看到原因
+pub async fn respond<S>(stream: &mut S) -> io::Result<Negotiated>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut peer = [0u8; PREAMBLE_LEN];
+    stream.read_exact(&mut peer).await
+        .map_err(|e| io::Error::new(e.kind(), format!("Peer closed before protocol preamble: {}", e)))?;
+    if &peer[..4] != MAGIC {
+        return Err(protocol_error(
+            "Peer did not send a PortForward preamble, check that remote_encryption is enabled on the peer".to_string(),
+        ));
+    }
+
+    let local = encode();
+    stream.write_all(&local).await?;
+
+    negotiate(&local, &peer, &peer, &local)
+}
+
+fn negotiate(
+    local: &[u8; PREAMBLE_LEN],
+    peer: &[u8; PREAMBLE_LEN],
+    initiator: &[u8; PREAMBLE_LEN],
+    responder: &[u8; PREAMBLE_LEN],
+) -> io::Result<Negotiated> {
+    let (peer_min, peer_max) = (peer[4], peer[5]);
+    let version = MAX_VERSION.min(peer_max);
+    if version < MIN_VERSION.max(peer_min) {
+        let upgrade = if peer_min > MAX_VERSION { "this end" } else { "the peer" };
+        return Err(protocol_error(format!(
+            "Protocol version mismatch: this end supports {}-{}, peer supports {}-{}, upgrade PortForward on {}",
+            local[4], local[5], peer_min, peer_max, upgrade
+        )));
+    }
+
+    let peer_features = u16::from_be_bytes([peer[6], peer[7]]);
+    let mut transcript = [0u8; PREAMBLE_LEN * 2];
+    transcript[..PREAMBLE_LEN].copy_from_slice(initiator);
+    transcript[PREAMBLE_LEN..].copy_from_slice(responder);
+
+    Ok(Negotiated { features: SUPPORTED_FEATURES & peer_features, transcript })
+}
+
+fn protocol_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,675 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::{Semaphore, mpsc, oneshot, watch};
+use tokio::time::{Instant, Interval, MissedTickBehavior, interval_at, sleep_until, timeout};
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::buffer::PacketBuffer;
+use crate::compression::{COMPRESSION_OVERHEAD, Compression, Compressor};
+use crate::encryption::{SimpleEncryptionContext, encrypt_and_prepend_length};
+use crate::frame::{Frame, MAX_FRAME_OVERHEAD};
+use crate::mux::MuxStream;
+use crate::stream::BoxStream;
+
+// 加密链路每个方向的流控窗口：发送方最多有这么多字节未被对端确认转发
+const INITIAL_WINDOW: usize = 256 * 1024;
+// 接收方累计转发这么多字节后发送窗口更新
+const WINDOW_UPDATE_THRESHOLD: usize = INITIAL_WINDOW / 4;
+// 连续这么多个保活周期没有收到任何帧时认为对端失联
+const KEEPALIVE_MISSES: u32 = 3;
+// 连接结束时等待对端关闭加密链路的最长时间
+const CLOSE_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const READ_BUFFER_SIZE: usize = 4096;
+
+// 连接的一侧：明文链路直接读写，加密链路按帧读写
+pub struct Link {
+    reader: LinkReader,
+    writer: LinkWriter,
+}
+
+pub struct LinkReader {
+    reader: ReadHalf<BoxStream>,
+    read_buffer: Vec<u8>,
+    decoder: Option<FrameDecoder>,
+    // 多路复用流被对端复位的原因
+    peer_reset: Option<Arc<Mutex<Option<String>>>>,
+}
+
+struct FrameDecoder {
+    buffer: PacketBuffer,
+    ctx: SimpleEncryptionContext,
+    decompressor: Compressor,
+}
+
+pub struct LinkWriter {
+    writer: WriteHalf<BoxStream>,
+    encoder: Option<FrameEncoder>,
+    // 复位多路复用流的通道
+    reset: Option<oneshot::Sender<String>>,
+    peer_reset: Option<Arc<Mutex<Option<String>>>>,
+}
+
+struct FrameEncoder {
+    ctx: SimpleEncryptionContext,
+    compressor: Compressor,
+}
+
+impl Link {
+    pub fn plain(stream: BoxStream) -> Self {
+        Self::new(stream, None, None)
+    }
+
+    pub fn encrypted(
+        stream: BoxStream,
+        sender: SimpleEncryptionContext,
+        receiver: SimpleEncryptionContext,
+        compression: Compression,
+    ) -> Self {
+        Self::new(
+            stream,
+            Some(FrameEncoder { ctx: sender, compressor: Compressor::new(compression) }),
+            Some(FrameDecoder { buffer: PacketBuffer::new(), ctx: receiver, decompressor: Compressor::new(compression) }),
+        )
+    }
+
+    // 多路复用的流：按明文读写，出错关闭时复位流，原因发给对端
+    pub fn stream(stream: MuxStream) -> Self {
+        let mut link = Self::new(Box::new(stream.stream), None, None);
+        link.reader.peer_reset = Some(stream.peer_reset.clone());
+        link.writer.peer_reset = Some(stream.peer_reset);
+        link.writer.reset = Some(stream.reset);
+        link
+    }
+
+    fn new(stream: BoxStream, encoder: Option<FrameEncoder>, decoder: Option<FrameDecoder>) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        Self {
+            reader: LinkReader { reader, read_buffer: vec![0u8; READ_BUFFER_SIZE], decoder, peer_reset: None },
+            writer: LinkWriter { writer, encoder, reset: None, peer_reset: None },
+        }
+    }
+
+    pub fn reader(&mut self) -> &mut LinkReader {
+        &mut self.reader
+    }
+
+    pub fn writer(&mut self) -> &mut LinkWriter {
+        &mut self.writer
+    }
+
+    pub fn split(self) -> (LinkReader, LinkWriter) {
+        (self.reader, self.writer)
+    }
+
+    // 加密链路协商的压缩方式，明文链路为 None
+    pub fn compression(&self) -> Option<Compression> {
+        self.writer.encoder.as_ref().map(|encoder| encoder.compressor.compression())
+    }
+
+    // 压缩统计，例如 "zstd sent 1048576 -> 262144 bytes (25.0%), received ..."；未压缩的链路为空
+    pub fn compression_summary(&self) -> Option<String> {
+        let (encoder, decoder) = (self.writer.encoder.as_ref()?, self.reader.decoder.as_ref()?);
+        let compression = encoder.compressor.compression();
+        if compression == Compression::None {
+            return None;
+        }
+        Some(format!(
+            "{} sent {}, received {}",
+            compression.name(),
+            encoder.compressor.summary(),
+            decoder.decompressor.summary()
+        ))
+    }
+}
+
+impl LinkReader {
+    fn is_encrypted(&self) -> bool {
+        self.decoder.is_some()
+    }
+
+    // 读取下一帧，链路关闭时返回 None；明文链路读到的数据都作为数据帧。
+    // 可以在 select! 中被取消：已读到的数据保存在 PacketBuffer 中
+    pub async fn read_frame(&mut self) -> io::Result<Option<Frame>> {
+        let Some(decoder) = self.decoder.as_mut() else {
+            let n = self.reader.read(&mut self.read_buffer).await?;
+            if n == 0
+                && let Some(e) = peer_reset_error(&self.peer_reset)
+            {
+                return Err(e);
+            }
+            return Ok((n > 0).then(|| Frame::Data(self.read_buffer[..n].to_vec())));
+        };
+
+        loop {
+            if let Some(frame) = decoder.buffer.try_read_packet(&mut decoder.ctx)? {
+                let decompressor = &mut decoder.decompressor;
+                return Ok(Some(frame.map_data(|data| decompressor.decompress(&data))?));
+            }
+
+            let n = self.reader.read(&mut self.read_buffer).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            decoder.buffer.push_data(&self.read_buffer[..n]);
+        }
+    }
+}
+
+impl LinkWriter {
+    fn is_encrypted(&self) -> bool {
+        self.encoder.is_some()
+    }
+
+    // 发送一帧；明文链路只能表达数据和 EOF（关闭写方向），其余控制帧忽略
+    pub async fn send(&mut self, frame: Frame) -> io::Result<()> {
+        let Some(encoder) = self.encoder.as_mut() else {
+            let result = match frame {
+                Frame::Data(data) => self.writer.write_all(&data).await,
+                Frame::Eof => self.writer.shutdown().await,
+                _ => Ok(()),
+            };
+            return result.map_err(|e| peer_reset_error(&self.peer_reset).unwrap_or(e));
+        };
+
+        // 先按单帧容量拆分再压缩，接收方每帧都能独立解压和解码
+        let max_len = encoder.ctx.padding().frame_capacity() - MAX_FRAME_OVERHEAD - COMPRESSION_OVERHEAD;
+        let mut encrypted = Vec::new();
+        for frame in frame.split(max_len) {
+            let compressor = &mut encoder.compressor;
+            let frame = frame.map_data(|data| compressor.compress(&data))?;
+            encrypted.extend(encrypt_and_prepend_length(&frame.encode(), &mut encoder.ctx).await?);
+        }
+        self.writer.write_all(&encrypted).await
+    }
+
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        self.writer.shutdown().await
+    }
+
+    // 出错关闭前把原因告诉加密链路或多路复用流的对端，尽力而为
+    pub async fn close(&mut self, reason: &str) {
+        if self.is_encrypted() {
+            let _ = self.send(Frame::Close(reason.to_string())).await;
+            let _ = self.writer.shutdown().await;
+        } else if let Some(reset) = self.reset.take() {
+            let _ = reset.send(reason.to_string());
+        }
+    }
+}
+
+// 多路复用流被对端复位时，读到结尾或写入失败都以复位的原因报告
+fn peer_reset_error(peer_reset: &Option<Arc<Mutex<Option<String>>>>) -> Option<io::Error> {
+    let reason = peer_reset.as_ref()?.lock().unwrap_or_else(PoisonError::into_inner).clone()?;
+    Some(io::Error::new(io::ErrorKind::ConnectionAborted, format!("Stream reset by peer: {}", reason)))
+}
+
+// 每条链路在两个转发方向之间共享的状态
+struct LinkState {
+    // 需要在这条链路上发出的控制帧（回复 ping、窗口更新），由写这条链路的方向发送
+    control: mpsc::UnboundedSender<Frame>,
+    // 这条链路上还能发送的数据额度
+    credit: Semaphore,
+    // 最近一次从这条链路收到帧的时间
+    last_seen: Mutex<Instant>,
+    // 最近一次从这条链路收到数据的时间，控制帧不算在内
+    last_data: Mutex<Option<Instant>>,
+}
+
+impl LinkState {
+    fn new() -> (Self, mpsc::UnboundedReceiver<Frame>) {
+        let (control, receiver) = mpsc::unbounded_channel();
+        let state = Self {
+            control,
+            credit: Semaphore::new(INITIAL_WINDOW),
+            last_seen: Mutex::new(Instant::now()),
+            last_data: Mutex::new(None),
+        };
+        (state, receiver)
+    }
+
+    fn touch(&self) {
+        *self.last_seen.lock().unwrap_or_else(PoisonError::into_inner) = Instant::now();
+    }
+
+    fn touch_data(&self) {
+        *self.last_data.lock().unwrap_or_else(PoisonError::into_inner) = Some(Instant::now());
+    }
+
+    fn last_data(&self) -> Option<Instant> {
+        *self.last_data.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// 转发连接的时间限制，None 表示不限制
+#[derive(Clone, Copy, Default)]
+pub struct Limits {
+    // 两个方向都没有数据的最长时间
+    pub idle_timeout: Option<Duration>,
+    // 连接的最长存活时间
+    pub max_lifetime: Option<Duration>,
+    // 开始转发后等待客户端发来第一个数据的时间
+    pub first_byte_timeout: Option<Duration>,
+}
+
+// 在两侧之间双向转发，直到两个方向都结束；出错或超过时间限制时把原因发给加密链路的对端
+pub async fn relay(local: &mut Link, remote: &mut Link, keepalive: Option<Duration>, limits: Limits) -> io::Result<()> {
+    let (local_state, local_control) = LinkState::new();
+    let (remote_state, remote_control) = LinkState::new();
+    let (done, _) = watch::channel(0u8);
+
+    let pumps = async {
+        tokio::try_join!(
+            pump(&mut local.reader, &mut remote.writer, &local_state, &remote_state, remote_control, &done, keepalive),
+            pump(&mut remote.reader, &mut local.writer, &remote_state, &local_state, local_control, &done, keepalive),
+        )
+        .map(|_| ())
+    };
+    let result = tokio::select! {
+        result = pumps => result,
+        e = enforce_limits(limits, &local_state, &remote_state) => Err(e),
+    };
+
+    if let Err(e) = &result {
+        let reason = e.to_string();
+        local.writer.close(&reason).await;
+        remote.writer.close(&reason).await;
+    }
+    result
+}
+
+// 等到某个时间限制被突破，返回关闭连接的原因；没有配置限制时一直等待
+async fn enforce_limits(limits: Limits, local: &LinkState, remote: &LinkState) -> io::Error {
+    let started = Instant::now();
+    loop {
+        let last_data = local.last_data().max(remote.last_data()).unwrap_or(started);
+        let client_silent = local.last_data().is_none();
+        let deadlines = [
+            limits.max_lifetime.map(|limit| started + limit),
+            limits.idle_timeout.map(|limit| last_data + limit),
+            limits.first_byte_timeout.filter(|_| client_silent).map(|limit| started + limit),
+        ];
+        let Some(deadline) = deadlines.into_iter().flatten().min() else {
+            return std::future::pending().await;
+        };
+        sleep_until(deadline).await;
+
+        // 醒来时重新读取数据时间，期间有数据到达则继续等待
+        let now = Instant::now();
+        let last_data = local.last_data().max(remote.last_data()).unwrap_or(started);
+        let reason = if let Some(limit) = limits.max_lifetime.filter(|limit| now >= started + *limit) {
+            format!("Maximum lifetime of {:.1}s reached", limit.as_secs_f64())
+        } else if let Some(limit) = limits.first_byte_timeout.filter(|limit| local.last_data().is_none() && now >= started + *limit) {
+            format!("First byte timeout: client sent no data within {:.1}s", limit.as_secs_f64())
+        } else if let Some(limit) = limits.idle_timeout.filter(|limit| now >= last_data + *limit) {
+            format!("Idle timeout: no data in either direction for {:.1}s", limit.as_secs_f64())
+        } else {
+            continue;
+        };
+        return io::Error::new(io::ErrorKind::TimedOut, reason);
+    }
+}
+
+// 一个转发方向：从 source 读，向 sink 写；同时负责 sink 链路上的控制帧和保活
+async fn pump(
+    source: &mut LinkReader,
+    sink: &mut LinkWriter,
+    source_state: &LinkState,
+    sink_state: &LinkState,
+    mut sink_control: mpsc::UnboundedReceiver<Frame>,
+    done: &watch::Sender<u8>,
+    keepalive: Option<Duration>,
+) -> io::Result<()> {
+    // 等待发往 sink 的数据帧和 EOF
+    let mut pending: VecDeque<Frame> = VecDeque::new();
+    // 已收到但尚未给对端发送窗口更新的字节数，包括 pending 中的数据
+    let mut unacknowledged = 0usize;
+    let mut acknowledged = 0usize;
+    let mut source_open = true;
+    let mut eof_received = false;
+
+    let mut keepalive = Keepalive::new(keepalive.filter(|_| sink.is_encrypted()));
+    let mut done_receiver = done.subscribe();
+
+    loop {
+        tokio::select! {
+            // 加密链路一直读取，以便及时处理控制帧；对端受窗口限制，积压的数据有上限。
+            // 明文链路在积压的数据发出之前不再读取，由 TCP 自身反压
+            frame = source.read_frame(), if source_open && (source.is_encrypted() || pending.is_empty()) => {
+                let Some(frame) = frame? else {
+                    source_open = false;
+                    if !eof_received {
+                        if source.is_encrypted() {
+                            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Peer closed the encrypted link without EOF"));
+                        }
+                        eof_received = true;
+                        pending.push_back(Frame::Eof);
+                    }
+                    continue;
+                };
+                source_state.touch();
+
+                match frame {
+                    Frame::Data(_) if eof_received => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Peer sent data after EOF"));
+                    }
+                    Frame::Data(data) => {
+                        source_state.touch_data();
+                        if source.is_encrypted() {
+                            unacknowledged += data.len();
+                            if unacknowledged > INITIAL_WINDOW {
+                                return Err(io::Error::new(io::ErrorKind::InvalidData, "Peer exceeded the flow control window"));
+                            }
+                        }
+                        pending.push_back(Frame::Data(data));
+                    }
+                    Frame::Eof => {
+                        eof_received = true;
+                        pending.push_back(Frame::Eof);
+                    }
+                    Frame::Ping(id) => {
+                        let _ = source_state.control.send(Frame::Pong(id));
+                    }
+                    Frame::Pong(_) => {}
+                    Frame::WindowUpdate(increment) => {
+                        add_credit(&source_state.credit, increment, INITIAL_WINDOW)?;
+                    }
+                    Frame::Close(reason) => {
+                        return Err(io::Error::new(io::ErrorKind::ConnectionAborted, format!("Peer closed the connection: {}", reason)));
+                    }
+                    Frame::Open | Frame::Stream(..) => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected stream frame on a single-stream link"));
+                    }
+                    Frame::Register(_) => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected reverse tunnel registration on a forwarded link"));
+                    }
+                }
+            }
+
+            // 发送积压的数据，加密链路需要先取得对端给的额度
+            permit = acquire_credit(sink_state, sink.is_encrypted(), pending.front()), if !pending.is_empty() => {
+                permit?;
+                let Some(frame) = pending.pop_front() else { continue };
+                let forwarded = match &frame {
+                    Frame::Data(data) => data.len(),
+                    _ => 0,
+                };
+                let is_eof = frame == Frame::Eof;
+                sink.send(frame).await?;
+
+                if is_eof {
+                    done.send_modify(|finished| *finished += 1);
+                }
+
+                // 数据转发出去后再给对端窗口更新，慢速的接收方会让发送方停下来
+                if source.is_encrypted() {
+                    acknowledged += forwarded;
+                    if acknowledged >= WINDOW_UPDATE_THRESHOLD || (is_eof && acknowledged > 0) {
+                        let _ = source_state.control.send(Frame::WindowUpdate(acknowledged as u32));
+                        unacknowledged -= acknowledged;
+                        acknowledged = 0;
+                    }
+                }
+            }
+
+            Some(frame) = sink_control.recv() => {
+                sink.send(frame).await?;
+            }
+
+            ping = keepalive.tick(&sink_state.last_seen) => {
+                sink.send(ping?).await?;
+            }
+
+            finished = async { done_receiver.wait_for(|finished| *finished >= 2).await.map(|_| ()) } => {
+                finished.map_err(io::Error::other)?;
+                break;
+            }
+        }
+    }
+
+    // 两个方向都结束：关闭加密链路的写方向，并读完对端剩余的帧，双方都能正常结束连接
+    if sink.is_encrypted() {
+        let _ = sink.writer.shutdown().await;
+    }
+    if source.is_encrypted() && source_open {
+        let _ = timeout(CLOSE_DRAIN_TIMEOUT, async {
+            while let Ok(Some(_)) = source.read_frame().await {}
+        })
+        .await;
+    }
+    Ok(())
+}
+
+// 对端的窗口更新只能归还已经用掉的额度，可用额度超过窗口说明对端出错或恶意，拒绝以免信号量溢出
+pub fn add_credit(credit: &Semaphore, increment: u32, window: usize) -> io::Result<()> {
+    if credit.available_permits().saturating_add(increment as usize) > window {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Peer window update of {} bytes exceeds the flow control window", increment)));
+    }
+    credit.add_permits(increment as usize);
+    Ok(())
+}
+
+async fn acquire_credit(state: &LinkState, encrypted: bool, frame: Option<&Frame>) -> io::Result<()> {
+    if let (true, Some(Frame::Data(data))) = (encrypted, frame)
+        && !data.is_empty()
+    {
+        let permits = u32::try_from(data.len()).map_err(io::Error::other)?;
+        state.credit.acquire_many(permits).await.map_err(io::Error::other)?.forget();
+    }
+    Ok(())
+}
+
+// 保活：每个周期发送一个 Ping，对端连续 KEEPALIVE_MISSES 个周期没有任何帧时报超时。
+// 加密链路的转发和多路复用会话共用
+pub struct Keepalive {
+    ticker: Option<(Interval, Duration)>,
+    ping_id: u64,
+}
+
+impl Keepalive {
+    pub fn new(period: Option<Duration>) -> Self {
+        let ticker = period.map(|period| {
+            let mut interval = interval_at(Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            (interval, period)
+        });
+        Self { ticker, ping_id: 0 }
+    }
+
+    // 等到下一个周期，返回要发送的 Ping；last_seen 是最后一次收到对端帧的时间。未开启保活时永远等待
+    pub async fn tick(&mut self, last_seen: &Mutex<Instant>) -> io::Result<Frame> {
+        let Some((interval, period)) = &mut self.ticker else {
+            return std::future::pending().await;
+        };
+        interval.tick().await;
+        let idle = last_seen.lock().unwrap_or_else(PoisonError::into_inner).elapsed();
+        if idle > *period * KEEPALIVE_MISSES {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Keepalive timeout: no frames from peer for {:.1}s", idle.as_secs_f64()),
+            ));
+        }
+        self.ping_id += 1;
+        Ok(Frame::Ping(self.ping_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::{CipherSuite, FRAME_HEADER_LEN, KEY_LEN, KeyRing, SharedKeyRing};
+    use crate::handshake::{self, SessionKeys};
+    use crate::identity::load_allowed_identities;
+    use crate::mux::{MuxSession, Role};
+    use crate::padding::Padding;
+
+    use tokio::io::DuplexStream;
+
+    use std::collections::BTreeMap;
+    use std::sync::RwLock;
+
+    // 通过真实握手得到发起方和响应方的会话密钥，握手使用 a、b 这对管道
+    async fn handshake_pair(a: &mut DuplexStream, b: &mut DuplexStream, padding: Padding, compression: Compression) -> (SessionKeys, SessionKeys) {
+        let keyring: SharedKeyRing = Arc::new(RwLock::new(KeyRing::new(BTreeMap::from([(1, [7u8; KEY_LEN])]), 1).unwrap()));
+        let allowed = load_allowed_identities(&[]).unwrap();
+        let (initiator, responder) = tokio::join!(
+            handshake::initiate(a, &keyring, CipherSuite::default(), compression, false, None),
+            handshake::respond(b, &keyring, CipherSuite::default(), compression, &allowed),
+        );
+        let (mut initiator, mut responder) = (initiator.unwrap(), responder.unwrap());
+        initiator.sender.set_padding(padding.clone());
+        responder.sender.set_padding(padding);
+        (initiator, responder)
+    }
+
+    // 通过真实握手建立一对加密链路
+    async fn link_pair(padding: Padding, compression: Compression) -> (Link, Link) {
+        let (mut a, mut b) = tokio::io::duplex(1 << 20);
+        let (initiator, responder) = handshake_pair(&mut a, &mut b, padding, compression).await;
+        (
+            Link::encrypted(Box::new(a), initiator.sender, initiator.receiver, initiator.compression),
+            Link::encrypted(Box::new(b), responder.sender, responder.receiver, responder.compression),
+        )
+    }
+
+    // 握手后两端各自读写一条单独的管道，测试从管道另一端截取或注入原始加密帧
+    async fn tapped_pair() -> (Link, DuplexStream, Link, DuplexStream) {
+        let (mut a, mut b) = tokio::io::duplex(1 << 20);
+        let (initiator, responder) = handshake_pair(&mut a, &mut b, Padding::None, Compression::None).await;
+        let (initiator_wire, initiator_tap) = tokio::io::duplex(1 << 20);
+        let (responder_wire, responder_tap) = tokio::io::duplex(1 << 20);
+        (
+            Link::encrypted(Box::new(initiator_wire), initiator.sender, initiator.receiver, initiator.compression),
+            initiator_tap,
+            Link::encrypted(Box::new(responder_wire), responder.sender, responder.receiver, responder.compression),
+            responder_tap,
+        )
+    }
+
+    // 从管道读出一个完整的加密帧（帧头和密文）
+    async fn read_raw_frame(tap: &mut DuplexStream) -> Vec<u8> {
+        let mut frame = vec![0u8; FRAME_HEADER_LEN];
+        tap.read_exact(&mut frame).await.unwrap();
+        let len = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+        frame.resize(FRAME_HEADER_LEN + len, 0);
+        tap.read_exact(&mut frame[FRAME_HEADER_LEN..]).await.unwrap();
+        frame
+    }
+
+    fn all_frames() -> Vec<Frame> {
+        let long_data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let long_reason = "连接远程地址失败 Connect remote addr 127.0.0.1:19003 failed: Connection refused ".repeat(20);
+        vec![
+            Frame::Data(b"hello".to_vec()),
+            Frame::Data(long_data.clone()),
+            Frame::Ping(1),
+            Frame::Pong(u64::MAX),
+            Frame::Eof,
+            Frame::Close("short".to_string()),
+            Frame::Close(long_reason.clone()),
+            Frame::WindowUpdate(65536),
+            Frame::Register("web".to_string()),
+            Frame::Stream(3, Box::new(Frame::Open)),
+            Frame::Stream(3, Box::new(Frame::Data(long_data))),
+            Frame::Stream(3, Box::new(Frame::Eof)),
+            Frame::Stream(3, Box::new(Frame::Close(long_reason))),
+            Frame::Stream(3, Box::new(Frame::WindowUpdate(1))),
+        ]
+    }
+
+    // 每种帧经过拆分、压缩、填充、加密后，接收方都能逐帧解码；数据完整，过长的关闭原因被截短
+    #[tokio::test]
+    async fn frames_round_trip_through_padding_and_encryption() {
+        let paddings = [
+            Padding::None,
+            Padding::Constant { size: 64 },
+            Padding::Buckets { sizes: vec![64, 512] },
+            Padding::Random { max: 100 },
+        ];
+        for padding in paddings {
+            for compression in [Compression::None, Compression::Zstd, Compression::Lz4] {
+                let (mut sender, mut receiver) = link_pair(padding.clone(), compression).await;
+                let max_len = padding.frame_capacity() - MAX_FRAME_OVERHEAD - COMPRESSION_OVERHEAD;
+                for frame in all_frames() {
+                    sender.writer.send(frame.clone()).await.unwrap();
+                    for expected in frame.clone().split(max_len) {
+                        assert!(expected.encode().len() <= padding.frame_capacity());
+                        let received = receiver.reader.read_frame().await.unwrap().unwrap();
+                        assert_eq!(received, expected, "padding {} compression {:?}", padding.name(), compression);
+                    }
+                }
+            }
+        }
+    }
+
+    // 重放、调换顺序和反射回发送方的帧都被拒绝
+    #[tokio::test]
+    async fn replayed_reordered_and_reflected_frames_are_rejected() {
+        let (mut initiator, mut initiator_tap, mut responder, mut responder_tap) = tapped_pair().await;
+        initiator.writer.send(Frame::Ping(1)).await.unwrap();
+        initiator.writer.send(Frame::Ping(2)).await.unwrap();
+        let first = read_raw_frame(&mut initiator_tap).await;
+
+        // 发起方发出的帧反射回发起方：序号对得上，但方向不同，解密失败
+        initiator_tap.write_all(&first).await.unwrap();
+        let error = initiator.reader.read_frame().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("wrong direction"), "{}", error);
+
+        // 同一帧交给响应方两次
+        responder_tap.write_all(&first).await.unwrap();
+        assert_eq!(responder.reader.read_frame().await.unwrap(), Some(Frame::Ping(1)));
+        responder_tap.write_all(&first).await.unwrap();
+        let error = responder.reader.read_frame().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("Replayed"), "{}", error);
+
+        // 另一对链路上先交第二帧
+        let (mut initiator, mut initiator_tap, mut responder, mut responder_tap) = tapped_pair().await;
+        initiator.writer.send(Frame::Ping(1)).await.unwrap();
+        initiator.writer.send(Frame::Ping(2)).await.unwrap();
+        let _first = read_raw_frame(&mut initiator_tap).await;
+        let second = read_raw_frame(&mut initiator_tap).await;
+        responder_tap.write_all(&second).await.unwrap();
+        let error = responder.reader.read_frame().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("Out-of-order"), "{}", error);
+    }
+
+    // 窗口更新只能归还用掉的额度，超出窗口的增量被拒绝
+    #[test]
+    fn window_updates_cannot_exceed_the_window() {
+        let credit = Semaphore::new(100);
+        credit.try_acquire_many(60).unwrap().forget();
+        add_credit(&credit, 60, 100).unwrap();
+        assert_eq!(credit.available_permits(), 100);
+        assert_eq!(add_credit(&credit, 1, 100).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(add_credit(&credit, u32::MAX, 100).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(credit.available_permits(), 100);
+    }
+
+    // 多路复用流上的时间限制以流的 Close 复位对端，对端的连接不会停在半关闭状态
+    #[tokio::test]
+    async fn limits_reset_the_far_side_of_a_multiplexed_stream() {
+        let (initiator, responder) = link_pair(Padding::None, Compression::None).await;
+        let (session, _) = MuxSession::start(initiator, Role::Initiator, None, "initiator".to_string(), None);
+        let (_, mut accepted) = MuxSession::start(responder, Role::Responder, None, "responder".to_string(), None);
+
+        let (_, stream) = session.open().unwrap();
+        let (client, _client_peer) = tokio::io::duplex(1024);
+        let mut local = Link::plain(Box::new(client));
+        let mut remote = Link::stream(stream);
+        let limits = Limits { idle_timeout: Some(Duration::from_millis(200)), max_lifetime: None, first_byte_timeout: None };
+        let error = relay(&mut local, &mut remote, None, limits).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+
+        let (_, far) = accepted.recv().await.unwrap();
+        let mut far = Link::stream(far);
+        let error = timeout(Duration::from_secs(5), far.reader.read_frame()).await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionAborted);
+        assert!(error.to_string().contains("Idle timeout"), "{}", error);
+    }
+}
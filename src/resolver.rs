@@ -0,0 +1,176 @@
+use tokio::net::{TcpStream, lookup_host};
+use tokio::task::JoinSet;
+use tokio::time::{Instant, sleep};
+
+use serde::Deserialize;
+use tklog::{async_error, async_info};
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+// 远程主机名的解析：结果缓存 dns_refresh 秒后重新解析，解析失败时继续使用上次的地址；
+// 连接时按地址族偏好排列全部地址，按 Happy Eyeballs 交错发起连接
+
+// 默认的重新解析间隔和 Happy Eyeballs 启动下一个地址前的等待时间（RFC 8305 建议 250ms）
+pub const DEFAULT_DNS_REFRESH: f64 = 60.0;
+pub const DEFAULT_HAPPY_EYEBALLS_DELAY: f64 = 0.25;
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFamily {
+    // 按解析结果的顺序，IPv4 和 IPv6 交错尝试
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+    PreferIpv4,
+    PreferIpv6,
+}
+
+impl AddressFamily {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AddressFamily::Any => "any",
+            AddressFamily::Ipv4 => "ipv4",
+            AddressFamily::Ipv6 => "ipv6",
+            AddressFamily::PreferIpv4 => "prefer_ipv4",
+            AddressFamily::PreferIpv6 => "prefer_ipv6",
+        }
+    }
+}
+
+struct Cached {
+    addrs: Vec<SocketAddr>,
+    expires: Instant,
+}
+
+pub struct Resolver {
+    name: String,
+    refresh: Duration,
+    family: AddressFamily,
+    delay: Duration,
+    cache: Mutex<HashMap<String, Cached>>,
+}
+
+impl Resolver {
+    pub fn new(name: String, refresh: Duration, family: AddressFamily, delay: Duration) -> Self {
+        Self { name, refresh, family, delay, cache: Mutex::new(HashMap::new()) }
+    }
+
+    // 解析 host:port，返回按偏好排好序的全部地址
+    pub async fn resolve(&self, addr: &str) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(literal) = addr.parse::<SocketAddr>() {
+            return self.order(addr, vec![literal]);
+        }
+
+        let previous = {
+            let cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+            match cache.get(addr) {
+                Some(cached) if cached.expires > Instant::now() => return Ok(cached.addrs.clone()),
+                Some(cached) => Some(cached.addrs.clone()),
+                None => None,
+            }
+        };
+
+        let resolved = match lookup_host(addr).await {
+            Ok(found) => self.order(addr, found.collect()),
+            Err(e) => Err(e),
+        };
+        let addrs = match (resolved, previous) {
+            (Ok(addrs), previous) => {
+                if previous.as_ref() != Some(&addrs) {
+                    async_info!("[ ",self.name," ] ",addr," resolved to ",join(&addrs));
+                }
+                addrs
+            }
+            // 解析失败时沿用上次的结果，下次连接再重新解析
+            (Err(e), Some(previous)) => {
+                async_error!("[ ",self.name," ] Resolve ",addr," failed: ",e.to_string(),", using previous addresses");
+                return Ok(previous);
+            }
+            (Err(e), None) => return Err(e),
+        };
+
+        let expires = Instant::now() + self.refresh;
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner).insert(addr.to_string(), Cached { addrs: addrs.clone(), expires });
+        Ok(addrs)
+    }
+
+    // 按 Happy Eyeballs 连接：依次启动每个地址，上一个失败或等待 delay 后启动下一个，最先成功的连接胜出
+    pub async fn connect(&self, addr: &str) -> io::Result<TcpStream> {
+        let mut candidates = self.resolve(addr).await?.into_iter();
+        let mut attempts = JoinSet::new();
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve to any address", addr));
+        if let Some(candidate) = candidates.next() {
+            attempts.spawn(TcpStream::connect(candidate));
+        }
+
+        while !attempts.is_empty() {
+            tokio::select! {
+                Some(result) = attempts.join_next() => match result {
+                    // 返回时丢弃 JoinSet，仍在进行的连接被取消
+                    Ok(Ok(stream)) => return Ok(stream),
+                    Ok(Err(e)) => {
+                        last_error = e;
+                        if let Some(candidate) = candidates.next() {
+                            attempts.spawn(TcpStream::connect(candidate));
+                        }
+                    }
+                    Err(e) => last_error = io::Error::other(e),
+                },
+                _ = sleep(self.delay), if candidates.len() > 0 => {
+                    if let Some(candidate) = candidates.next() {
+                        attempts.spawn(TcpStream::connect(candidate));
+                    }
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    // 去重并按地址族偏好排序，两种地址族都可用时交错排列
+    fn order(&self, addr: &str, found: Vec<SocketAddr>) -> io::Result<Vec<SocketAddr>> {
+        let mut ipv4 = Vec::new();
+        let mut ipv6 = Vec::new();
+        for candidate in &found {
+            let list = if candidate.is_ipv4() { &mut ipv4 } else { &mut ipv6 };
+            if !list.contains(candidate) {
+                list.push(*candidate);
+            }
+        }
+
+        let ipv6_first = match self.family {
+            AddressFamily::Ipv4 => { ipv6.clear(); false }
+            AddressFamily::Ipv6 => { ipv4.clear(); true }
+            AddressFamily::PreferIpv4 => false,
+            AddressFamily::PreferIpv6 => true,
+            AddressFamily::Any => found.first().is_some_and(|first| first.is_ipv6()),
+        };
+        let (first, second) = if ipv6_first { (ipv6, ipv4) } else { (ipv4, ipv6) };
+
+        let mut ordered = Vec::with_capacity(first.len() + second.len());
+        let mut first = first.into_iter();
+        let mut second = second.into_iter();
+        loop {
+            match (first.next(), second.next()) {
+                (None, None) => break,
+                (a, b) => ordered.extend(a.into_iter().chain(b)),
+            }
+        }
+
+        if ordered.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no {} address", addr, self.family.name()),
+            ));
+        }
+        Ok(ordered)
+    }
+}
+
+fn join(addrs: &[SocketAddr]) -> String {
+    addrs.iter().map(|addr| addr.to_string()).collect::<Vec<_>>().join(", ")
+}
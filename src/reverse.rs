@@ -0,0 +1,182 @@
+use tokio::sync::broadcast;
+use tokio::time::{sleep, timeout};
+
+use serde::Deserialize;
+use tklog::{async_error, async_info};
+
+use std::io;
+use std::time::Duration;
+
+use crate::address::{self, Listener};
+use crate::frame::Frame;
+use crate::handshake;
+use crate::mux::{MuxSession, Role};
+use crate::relay::Link;
+use crate::stream::BoxStream;
+use crate::{Forward, ForwardState, log_compression_mismatch, serve_multiplexed, with_timeout};
+
+// 反向隧道：内网端主动连接公网端并注册转发，公网端接受的客户端连接作为多路复用流交回内网端。
+// 隧道只反转 TCP 的连接方向：公网端仍是加密握手和多路复用的发起方（使用 remote_* 配置），
+// 内网端是响应方（使用 local_* 配置），就像公网端连到了内网端的监听地址。
+
+// 重连间隔从 1 秒开始翻倍，最长 30 秒
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(30);
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(10);
+// 隧道的连接和握手总是有超时，未配置 connect_timeout / handshake_timeout 时使用该值（秒），
+// 避免对端接受连接后不响应时公网端泄漏任务、内网端不再重连
+const DEFAULT_TUNNEL_TIMEOUT: f64 = 10.0;
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardRole {
+    // 普通转发：监听 local_addr，连接 remote_addr
+    #[default]
+    Forward,
+    // 公网端：监听 local_addr 接受客户端，在 remote_addr 上等待内网端连入
+    ReverseServer,
+    // 内网端：连接公网端的 local_addr 并注册，隧道中的连接转发到 remote_addr
+    ReverseClient,
+}
+
+impl ForwardRole {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ForwardRole::Forward => "forward",
+            ForwardRole::ReverseServer => "reverse_server",
+            ForwardRole::ReverseClient => "reverse_client",
+        }
+    }
+}
+
+// 公网端：接受内网端的隧道连接
+pub async fn tunnel_listening(
+    listener: Listener,
+    forward: Forward,
+    state: ForwardState,
+    mut stop_receiver: broadcast::Receiver<()>,
+) -> io::Result<()> {
+    async_info!("[ ",forward.name," ] Waiting for reverse tunnels on ",forward.remote_addr);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, peer)) => {
+                    tokio::spawn(accept_tunnel(forward.clone(), state.clone(), socket, peer));
+                }
+                Err(e) => {
+                    async_error!("[ ",forward.name," ] Accept reverse tunnel failed: ",e.to_string());
+                }
+            },
+            _ = stop_receiver.recv() => {
+                async_info!("[ ",forward.name," ] Reverse tunnel listener received stop signal");
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn accept_tunnel(forward: Forward, state: ForwardState, mut stream: BoxStream, peer: String) -> io::Result<()> {
+    async_info!("[ ",forward.name," ] ",peer," Reverse tunnel connected");
+
+    let (Some(pool), Some(keyring)) = (state.mux.as_ref(), state.keys.remote.as_ref()) else {
+        return Ok(());
+    };
+
+    let initiate = handshake::initiate(&mut stream, keyring, forward.cipher, forward.compression, true, state.remote_identity.as_deref());
+    let mut link = match with_timeout(Some(forward.handshake_timeout.unwrap_or(DEFAULT_TUNNEL_TIMEOUT)), initiate).await {
+        Ok(mut session) => {
+            log_compression_mismatch(&forward, &peer, "Tunnel", session.compression).await;
+            session.sender.set_padding(forward.padding.clone());
+            Link::encrypted(stream, session.sender, session.receiver, session.compression)
+        }
+        Err(e) => {
+            async_error!("[ ",forward.name," ] ",peer," Reverse tunnel handshake failed: ",e.to_string());
+            return Err(e);
+        }
+    };
+
+    // 内网端注册的名称必须与本转发一致，原样返回表示接受
+    let registered = match timeout(REGISTER_TIMEOUT, link.reader().read_frame()).await {
+        Ok(Ok(Some(Frame::Register(name)))) if name == forward.name => Ok(()),
+        Ok(Ok(Some(Frame::Register(name)))) => Err(format!("Forward {} is not served on this reverse tunnel address", name)),
+        Ok(Err(e)) => Err(e.to_string()),
+        _ => Err("Peer did not register a forward".to_string()),
+    };
+    if let Err(reason) = registered {
+        async_error!("[ ",forward.name," ] ",peer," Reverse tunnel rejected: ",reason);
+        link.writer().close(&reason).await;
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, reason));
+    }
+    link.writer().send(Frame::Register(forward.name.clone())).await?;
+
+    // 公网端只打开流，不接受对端打开的流
+    let keepalive = forward.keepalive.map(Duration::from_secs_f64);
+    let (session, _) = MuxSession::start(link, Role::Initiator, keepalive, format!("[ {} ] {} reverse tunnel", forward.name, peer), None);
+    pool.add(session);
+    async_info!("[ ",forward.name," ] ",peer," Reverse tunnel registered");
+    Ok(())
+}
+
+// 内网端：保持一条到公网端的隧道，断开后按退避间隔重连
+pub async fn reverse_dial(forward: Forward, state: ForwardState, mut stop_receiver: broadcast::Receiver<()>) -> io::Result<()> {
+    let mut retry = RETRY_MIN;
+    loop {
+        tokio::select! {
+            result = dial_tunnel(&forward, &state) => match result {
+                // 注册成功过的隧道断开后从最短间隔开始重连
+                Ok(()) => retry = RETRY_MIN,
+                Err(e) => {
+                    async_error!("[ ",forward.name," ] Reverse tunnel to ",forward.local_addr," failed: ",e.to_string());
+                }
+            },
+            _ = stop_receiver.recv() => break,
+        }
+
+        async_info!("[ ",forward.name," ] Reconnect reverse tunnel in ",retry.as_secs(),"s");
+        tokio::select! {
+            _ = sleep(retry) => {}
+            _ = stop_receiver.recv() => break,
+        }
+        retry = (retry * 2).min(RETRY_MAX);
+    }
+    async_info!("[ ",forward.name," ] Reverse tunnel received stop signal");
+    Ok(())
+}
+
+async fn dial_tunnel(forward: &Forward, state: &ForwardState) -> io::Result<()> {
+    async_info!("[ ",forward.name," ] Connect reverse tunnel to ",forward.local_addr);
+    let Some(keyring) = state.keys.local.as_ref() else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "reverse_client requires local_encryption"));
+    };
+
+    let connect = address::connect(&forward.local_addr, &state.resolver);
+    let mut stream = with_timeout(Some(forward.connect_timeout.unwrap_or(DEFAULT_TUNNEL_TIMEOUT)), connect).await?;
+    let respond = handshake::respond(&mut stream, keyring, forward.cipher, forward.compression, &state.allowed_identities);
+    let session = with_timeout(Some(forward.handshake_timeout.unwrap_or(DEFAULT_TUNNEL_TIMEOUT)), respond).await?;
+    if !session.multiplexed {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Peer is not a reverse_server forward"));
+    }
+
+    let mut peer = forward.local_addr.clone();
+    if let Some(name) = &session.peer_identity {
+        peer = format!("{} ( identity {} )", peer, name);
+    }
+    log_compression_mismatch(forward, &peer, "Tunnel", session.compression).await;
+    let mut sender = session.sender;
+    sender.set_padding(forward.padding.clone());
+    let mut link = Link::encrypted(stream, sender, session.receiver, session.compression);
+
+    link.writer().send(Frame::Register(forward.name.clone())).await?;
+    match timeout(REGISTER_TIMEOUT, link.reader().read_frame()).await {
+        Ok(Ok(Some(Frame::Register(_)))) => {}
+        Ok(Ok(Some(Frame::Close(reason)))) => {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("Registration rejected: {}", reason)));
+        }
+        Ok(Err(e)) => return Err(e),
+        _ => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Peer did not accept the registration")),
+    }
+    async_info!("[ ",forward.name," ] Reverse tunnel registered with ",peer);
+
+    serve_multiplexed(forward.clone(), state.clone(), forward.local_addr.clone(), peer, link).await;
+    Ok(())
+}
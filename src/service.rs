@@ -1,7 +1,9 @@
 use std::process::Command;
 use std::path::Path;
 use std::io;
+#[cfg(target_os = "windows")]
 use tokio::sync::broadcast;
+#[cfg(target_os = "windows")]
 use crate::start_listen;
 #[cfg(target_os = "windows")]
 use windows_service::{
@@ -202,7 +204,6 @@ pub fn uninstall() -> windows_service::Result<()> {
 
 
 #[cfg(not(target_os = "windows"))]
-
 pub fn install_linux(config_path: String, log_path: String) -> io::Result<()>{
 
 
@@ -252,8 +253,6 @@ WantedBy=multi-user.target
 }
 
 #[cfg(not(target_os = "windows"))]
-
-
 pub fn uninstall_linux() -> io::Result<()> {
 
     // Stop the service if running
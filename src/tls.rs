@@ -0,0 +1,192 @@
+use std::io;
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use serde::Deserialize;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+// 本地监听的 TLS 配置，配置 client_ca 时要求客户端证书（mTLS）
+#[derive(Deserialize, Clone)]
+pub struct LocalTls {
+    pub cert: String,
+    pub key: String,
+    pub client_ca: Option<String>,
+}
+
+// 连接远程目标时的 TLS 配置，未配置 ca 时使用内置的公共根证书
+#[derive(Deserialize, Clone)]
+pub struct RemoteTls {
+    pub sni: Option<String>,
+    pub ca: Option<String>,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+// 启动时创建的 TLS 连接器和配置的服务器名
+#[derive(Clone)]
+pub struct RemoteTlsConnector {
+    pub connector: TlsConnector,
+    sni: Option<ServerName<'static>>,
+}
+
+impl RemoteTlsConnector {
+    // 连接 addr 时发送并校验的服务器名：配置了 sni 时使用 sni，否则使用该地址的主机部分
+    pub fn server_name(&self, addr: &str) -> io::Result<ServerName<'static>> {
+        match &self.sni {
+            Some(sni) => Ok(sni.clone()),
+            None => parse_server_name(host_of(addr)),
+        }
+    }
+}
+
+fn tls_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| tls_error(format!("Read certificates from {} failure: {}", path, e)))?;
+    if certs.is_empty() {
+        return Err(tls_error(format!("No certificate found in {}", path)));
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| tls_error(format!("Read private key from {} failure: {}", path, e)))
+}
+
+fn load_roots(path: &str) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)
+            .map_err(|e| tls_error(format!("Invalid CA certificate in {}: {}", path, e)))?;
+    }
+    Ok(roots)
+}
+
+// 启动时加载证书并创建 TLS 接收器
+pub fn build_acceptor(config: &LocalTls) -> io::Result<TlsAcceptor> {
+    let certs = load_certs(&config.cert)?;
+    let key = load_private_key(&config.key)?;
+
+    let builder = rustls::ServerConfig::builder();
+    let builder = match &config.client_ca {
+        Some(path) => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(load_roots(path)?))
+                .build()
+                .map_err(|e| tls_error(format!("Client CA {}: {}", path, e)))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server_config = builder.with_single_cert(certs, key)
+        .map_err(|e| tls_error(format!("Certificate {} does not match key {}: {}", config.cert, config.key, e)))?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+// 启动时加载证书并创建 TLS 连接器，未配置 sni 时检查每个远程地址的主机部分都是有效的服务器名
+pub fn build_connector(config: &RemoteTls, remote_addrs: &[&str]) -> io::Result<RemoteTlsConnector> {
+    let builder = if config.insecure_skip_verify {
+        rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(SkipServerVerification::new()))
+    } else {
+        let roots = match &config.ca {
+            Some(path) => load_roots(path)?,
+            None => RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() },
+        };
+        rustls::ClientConfig::builder().with_root_certificates(roots)
+    };
+
+    let client_config = match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => builder.with_client_auth_cert(load_certs(cert)?, load_private_key(key)?)
+            .map_err(|e| tls_error(format!("Client certificate {} does not match key {}: {}", cert, key, e)))?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err(tls_error("cert and key must be configured together".to_string())),
+    };
+
+    let sni = match &config.sni {
+        Some(sni) => Some(parse_server_name(sni)?),
+        None => {
+            for addr in remote_addrs {
+                parse_server_name(host_of(addr))?;
+            }
+            None
+        }
+    };
+
+    Ok(RemoteTlsConnector {
+        connector: TlsConnector::from(Arc::new(client_config)),
+        sni,
+    })
+}
+
+fn parse_server_name(host: &str) -> io::Result<ServerName<'static>> {
+    ServerName::try_from(host.to_string()).map_err(|e| tls_error(format!("Invalid server name {}: {}", host, e)))
+}
+
+// 取地址中的主机部分，支持 host:port 和 [ipv6]:port
+fn host_of(addr: &str) -> &str {
+    let host = match addr.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => addr,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+// 跳过证书校验，仅用于实验环境；握手签名仍按正常算法校验
+#[derive(Debug)]
+struct SkipServerVerification(Arc<CryptoProvider>);
+
+impl SkipServerVerification {
+    fn new() -> Self {
+        Self(Arc::new(rustls::crypto::ring::default_provider()))
+    }
+}
+
+impl ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,400 @@
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{Instant, sleep_until};
+
+use serde::Deserialize;
+use tklog::{async_error, async_info};
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::address::Listener;
+use crate::compression::COMPRESSION_OVERHEAD;
+use crate::frame::{Frame, MAX_FRAME_OVERHEAD};
+use crate::handshake;
+use crate::relay::{Link, LinkReader, LinkWriter};
+use crate::resolver::Resolver;
+use crate::stream::BoxStream;
+use crate::{Forward, ForwardState, connect_with_retry, log_compression_mismatch, with_timeout};
+
+// UDP 转发：每个客户端地址对应一个连接到 remote_addr 的上游套接字，
+// 双向转发数据报，一段时间没有数据报时回收会话。
+// 开启 remote_encryption 时，每个会话改用一条加密 TCP 链路，每个数据报是链路上的一个数据帧；
+// 开启 local_encryption 的一端在 TCP 上接受这些链路，再把数据报以 UDP 发往 remote_addr。
+
+// 单个数据报的最大长度
+pub const MAX_DATAGRAM: usize = 65535;
+pub const DEFAULT_IDLE_TIMEOUT: f64 = 60.0;
+// 同时存在的会话数上限，达到上限后新客户端的数据报被丢弃
+pub const DEFAULT_MAX_SESSIONS: usize = 1024;
+// 等待发往上游的数据报数量，上游跟不上时丢弃新的数据报
+const SESSION_QUEUE: usize = 256;
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+// 会话的上游在会话任务中建立（UDP 套接字或加密链路），接收循环只把数据报放进发送队列，
+// 建立之前到达的数据报先排队
+#[derive(Clone)]
+struct Session {
+    queue: mpsc::Sender<Vec<u8>>,
+    last_seen: Arc<Mutex<Instant>>,
+}
+
+impl Session {
+    fn touch(&self) {
+        *self.last_seen.lock().unwrap_or_else(PoisonError::into_inner) = Instant::now();
+    }
+
+    fn last_seen(&self) -> Instant {
+        *self.last_seen.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // 队列满时丢弃，和 UDP 本身的语义一致
+    fn send(&self, datagram: &[u8]) {
+        let _ = self.queue.try_send(datagram.to_vec());
+    }
+}
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, Session>>>;
+
+pub async fn udp_listening(
+    socket: UdpSocket,
+    forward: Forward,
+    state: ForwardState,
+    mut stop_receiver: broadcast::Receiver<()>,
+) -> io::Result<()> {
+    let socket = Arc::new(socket);
+    let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+    let idle = Duration::from_secs_f64(forward.udp_idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT));
+    let max_sessions = forward.udp_max_sessions.unwrap_or(DEFAULT_MAX_SESSIONS);
+    // 达到会话上限后只记录一次，直到有新会话建立
+    let mut full = false;
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
+
+    async_info!("[ ",forward.name," ] Start UDP listening loop");
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buffer) => {
+                let (n, client) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        async_error!("[ ",forward.name," ] UDP receive failed: ",e.to_string());
+                        continue;
+                    }
+                };
+
+                let (existing, count) = {
+                    let sessions = sessions.lock().unwrap_or_else(PoisonError::into_inner);
+                    (sessions.get(&client).cloned(), sessions.len())
+                };
+                let session = match existing {
+                    Some(session) => session,
+                    None if count >= max_sessions => {
+                        if !full {
+                            full = true;
+                            async_error!("[ ",forward.name," ] ",client," Dropped datagram: ",max_sessions," UDP sessions already open");
+                        }
+                        continue;
+                    }
+                    None => {
+                        full = false;
+                        open_session(&forward, &state, &socket, &sessions, client, idle)
+                    }
+                };
+
+                session.touch();
+                session.send(&buffer[..n]);
+            }
+
+            _ = stop_receiver.recv() => {
+                async_info!("[ ",forward.name," ] Worker received stop signal for ");
+                return Ok(());
+            }
+        }
+    }
+}
+
+// 为新的客户端地址登记会话，并启动会话任务建立上游；DNS 解析和连接都不阻塞接收循环
+fn open_session(
+    forward: &Forward,
+    state: &ForwardState,
+    socket: &Arc<UdpSocket>,
+    sessions: &Sessions,
+    client: SocketAddr,
+    idle: Duration,
+) -> Session {
+    let (queue, receiver) = mpsc::channel(SESSION_QUEUE);
+    let session = Session {
+        queue,
+        last_seen: Arc::new(Mutex::new(Instant::now())),
+    };
+    sessions.lock().unwrap_or_else(PoisonError::into_inner).insert(client, session.clone());
+
+    if forward.remote_encryption {
+        let endpoint = Endpoint::Client { socket: socket.clone(), client, receiver };
+        tokio::spawn(run_tunnel_session(forward.clone(), state.clone(), sessions.clone(), client, endpoint, idle));
+    } else {
+        tokio::spawn(run_session(forward.clone(), state.clone(), socket.clone(), sessions.clone(), client, receiver, session.clone()));
+    }
+    session
+}
+
+// 绑定与 remote_addr 地址族一致的本地套接字并连接；UDP 无法判断连接是否成功，使用偏好的第一个地址
+async fn connect_upstream(resolver: &Resolver, remote_addr: &str) -> io::Result<UdpSocket> {
+    let remote = resolver.resolve(remote_addr).await?[0];
+    let bind_addr = if remote.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let upstream = UdpSocket::bind(bind_addr).await?;
+    upstream.connect(remote).await?;
+    Ok(upstream)
+}
+
+// 连接上游后双向转发数据报，空闲超时后回收会话
+async fn run_session(
+    forward: Forward,
+    state: ForwardState,
+    socket: Arc<UdpSocket>,
+    sessions: Sessions,
+    client: SocketAddr,
+    mut receiver: mpsc::Receiver<Vec<u8>>,
+    session: Session,
+) {
+    let idle = Duration::from_secs_f64(forward.udp_idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT));
+    let upstream = match connect_upstream(&state.resolver, &forward.remote_addr).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            sessions.lock().unwrap_or_else(PoisonError::into_inner).remove(&client);
+            async_error!("[ ",forward.name," ] ",client," Open UDP session to ",forward.remote_addr," failed: ",e.to_string());
+            return;
+        }
+    };
+    async_info!("[ ",forward.name," ] ",client," UDP session started to ",forward.remote_addr);
+
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
+    let reason = loop {
+        tokio::select! {
+            datagram = receiver.recv() => match datagram {
+                Some(datagram) => {
+                    if let Err(e) = upstream.send(&datagram).await {
+                        async_error!("[ ",forward.name," ] ",client," UDP send to ",forward.remote_addr," failed: ",e.to_string());
+                    }
+                }
+                None => break "session removed".to_string(),
+            },
+            received = upstream.recv(&mut buffer) => match received {
+                Ok(n) => {
+                    session.touch();
+                    if let Err(e) = socket.send_to(&buffer[..n], client).await {
+                        async_error!("[ ",forward.name," ] ",client," UDP send to client failed: ",e.to_string());
+                    }
+                }
+                // 上游不可达（ICMP）等错误结束会话，下一个数据报会重新建立
+                Err(e) => break format!("upstream error: {}", e),
+            },
+            _ = sleep_until(session.last_seen() + idle) => {
+                if session.last_seen().elapsed() >= idle {
+                    break format!("idle for {}s", idle.as_secs_f64());
+                }
+            }
+        }
+    };
+
+    sessions.lock().unwrap_or_else(PoisonError::into_inner).remove(&client);
+    async_info!("[ ",forward.name," ] ",client," UDP session closed: ",reason);
+}
+
+// 加密链路一端的 UDP 侧：监听套接字上的一个客户端，或者连接到 remote_addr 的上游套接字
+enum Endpoint {
+    Client {
+        socket: Arc<UdpSocket>,
+        client: SocketAddr,
+        receiver: mpsc::Receiver<Vec<u8>>,
+    },
+    Upstream(UdpSocket),
+}
+
+impl Endpoint {
+    // 从 UDP 侧收下一个数据报，会话结束时返回 None
+    async fn recv(&mut self, buffer: &mut [u8]) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            Endpoint::Client { receiver, .. } => Ok(receiver.recv().await),
+            Endpoint::Upstream(upstream) => {
+                let n = upstream.recv(buffer).await?;
+                Ok(Some(buffer[..n].to_vec()))
+            }
+        }
+    }
+
+    async fn send(&self, datagram: &[u8]) -> io::Result<()> {
+        match self {
+            Endpoint::Client { socket, client, .. } => socket.send_to(datagram, *client).await.map(|_| ()),
+            Endpoint::Upstream(upstream) => upstream.send(datagram).await.map(|_| ()),
+        }
+    }
+}
+
+// 发起端：为一个客户端会话建立加密链路
+async fn run_tunnel_session(
+    forward: Forward,
+    state: ForwardState,
+    sessions: Sessions,
+    client: SocketAddr,
+    endpoint: Endpoint,
+    idle: Duration,
+) {
+    let peer = client.to_string();
+    // 连接失败的原因已由 connect_remote 记录
+    if let Ok(link) = connect_with_retry(&forward, &state, &peer, Some(&forward.remote_addr), false).await {
+        async_info!("[ ",forward.name," ] ",peer," UDP session started over encrypted link to ",forward.remote_addr);
+        run_tunnel(&forward, &peer, link, endpoint, idle).await;
+    }
+    sessions.lock().unwrap_or_else(PoisonError::into_inner).remove(&client);
+}
+
+// 接收端：在 TCP 上接受加密链路，数据报以 UDP 发往 remote_addr
+pub async fn udp_tunnel_listening(
+    listener: Listener,
+    forward: Forward,
+    state: ForwardState,
+    mut stop_receiver: broadcast::Receiver<()>,
+) -> io::Result<()> {
+    async_info!("[ ",forward.name," ] Start listening loop for UDP over encrypted links");
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, peer)) => {
+                    async_info!("[ ",forward.name," ] receive connection from ",peer);
+                    tokio::spawn(serve_tunnel(forward.clone(), state.clone(), socket, peer));
+                }
+                Err(e) => {
+                    async_error!("[ ",forward.name," ] Accept failed: ",e.to_string());
+                }
+            },
+            _ = stop_receiver.recv() => {
+                async_info!("[ ",forward.name," ] Worker received stop signal for ");
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn serve_tunnel(forward: Forward, state: ForwardState, mut stream: BoxStream, mut peer: String) {
+    let Some(keyring) = state.keys.local.as_ref() else { return };
+
+    let respond = handshake::respond(&mut stream, keyring, forward.cipher, forward.compression, &state.allowed_identities);
+    let mut link = match with_timeout(forward.handshake_timeout, respond).await {
+        Ok(mut session) => {
+            if let Some(name) = session.peer_identity {
+                peer = format!("{} ( identity {} )", peer, name);
+                async_info!("[ ",forward.name," ] ",peer," authenticated");
+            }
+            log_compression_mismatch(&forward, &peer, "Local", session.compression).await;
+            session.sender.set_padding(forward.padding.clone());
+            let multiplexed = session.multiplexed;
+            let mut link = Link::encrypted(stream, session.sender, session.receiver, session.compression);
+            if multiplexed {
+                async_error!("[ ",forward.name," ] ",peer," rejected: multiplex is not supported for UDP forwards");
+                link.writer().close("multiplex is not supported for UDP forwards").await;
+                return;
+            }
+            link
+        }
+        Err(e) => {
+            async_error!("[ ",forward.name," ] ",peer," Local handshake failed: ",e.to_string());
+            return;
+        }
+    };
+
+    let upstream = match connect_upstream(&state.resolver, &forward.remote_addr).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            let reason = format!("Open UDP socket to {} failed: {}", forward.remote_addr, e);
+            async_error!("[ ",forward.name," ] ",peer," ",reason);
+            link.writer().close(&reason).await;
+            return;
+        }
+    };
+    async_info!("[ ",forward.name," ] ",peer," UDP session started to ",forward.remote_addr);
+
+    let idle = Duration::from_secs_f64(forward.udp_idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT));
+    run_tunnel(&forward, &peer, link, Endpoint::Upstream(upstream), idle).await;
+}
+
+// 在加密链路和 UDP 侧之间搬运数据报，结束时记录原因
+async fn run_tunnel(forward: &Forward, peer: &str, link: Link, mut endpoint: Endpoint, idle: Duration) {
+    let (mut reader, mut writer) = link.split();
+    match pump_tunnel(forward, peer, &mut reader, &mut writer, &mut endpoint, idle).await {
+        Ok(reason) => {
+            let _ = writer.shutdown().await;
+            async_info!("[ ",forward.name," ] ",peer," UDP session closed: ",reason);
+        }
+        Err(e) => {
+            writer.close(&e.to_string()).await;
+            async_error!("[ ",forward.name," ] ",peer," UDP session closed: ",e.to_string());
+        }
+    }
+}
+
+async fn pump_tunnel(
+    forward: &Forward,
+    peer: &str,
+    reader: &mut LinkReader,
+    writer: &mut LinkWriter,
+    endpoint: &mut Endpoint,
+    idle: Duration,
+) -> io::Result<String> {
+    // 每个数据报必须放进一个加密帧，否则接收方无法还原边界
+    let max_datagram = forward.padding.frame_capacity() - MAX_FRAME_OVERHEAD - COMPRESSION_OVERHEAD;
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
+    let mut last_seen = Instant::now();
+
+    loop {
+        tokio::select! {
+            datagram = endpoint.recv(&mut buffer) => {
+                let Some(datagram) = datagram? else {
+                    return Ok("session removed".to_string());
+                };
+                last_seen = Instant::now();
+                if datagram.len() > max_datagram {
+                    async_error!("[ ",forward.name," ] ",peer," Dropped a ",datagram.len()," byte datagram, at most ",max_datagram," bytes fit in one frame with this padding");
+                    continue;
+                }
+                writer.send(Frame::Data(datagram)).await?;
+            }
+
+            frame = reader.read_frame() => match frame? {
+                Some(Frame::Data(datagram)) => {
+                    last_seen = Instant::now();
+                    endpoint.send(&datagram).await?;
+                }
+                Some(Frame::Ping(id)) => writer.send(Frame::Pong(id)).await?,
+                Some(Frame::Close(reason)) => {
+                    return Err(io::Error::new(io::ErrorKind::ConnectionAborted, format!("Peer closed the connection: {}", reason)));
+                }
+                Some(Frame::Eof) | None => return Ok("closed by peer".to_string()),
+                Some(_) => {}
+            },
+
+            _ = sleep_until(last_seen + idle) => {
+                return Ok(format!("idle for {}s", idle.as_secs_f64()));
+            }
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
+use tokio::time::{MissedTickBehavior, interval, timeout};
+
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tklog::{async_error, async_info};
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::frame::Frame;
+use crate::relay::Link;
+use crate::{Forward, ForwardState, open_remote};
+
+// 多个后端：remote_addr 加上 backends 组成后端列表，每个连接按均衡策略选择一个；
+// 配置健康检查时跳过被判定为下线的后端
+
+// 一致性哈希环上每个后端的虚拟节点数，节点越多分布越均匀
+const VIRTUAL_NODES: usize = 64;
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+    Random,
+    // 按客户端 IP 做一致性哈希，同一客户端总是连到同一后端，增减后端只影响少量客户端
+    ConsistentHash,
+}
+
+impl Balance {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Balance::RoundRobin => "round_robin",
+            Balance::LeastConnections => "least_connections",
+            Balance::Random => "random",
+            Balance::ConsistentHash => "consistent_hash",
+        }
+    }
+}
+
+pub struct Backend {
+    addr: String,
+    // 正在使用该后端的连接数
+    active: AtomicUsize,
+    // 健康检查的结果，未配置健康检查时始终在线
+    healthy: AtomicBool,
+}
+
+impl Backend {
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+}
+
+// 选中的后端，连接结束时释放计数
+pub struct BackendGuard {
+    backend: Arc<Backend>,
+}
+
+impl BackendGuard {
+    pub fn addr(&self) -> &str {
+        &self.backend.addr
+    }
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.backend.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Upstreams {
+    backends: Vec<Arc<Backend>>,
+    balance: Balance,
+    next: AtomicUsize,
+    // 一致性哈希环：(哈希值, 后端下标)，按哈希值排序
+    ring: Vec<(u64, usize)>,
+}
+
+impl Upstreams {
+    pub fn new(addrs: Vec<String>, balance: Balance) -> Self {
+        let backends: Vec<Arc<Backend>> = addrs.into_iter()
+            .map(|addr| Arc::new(Backend { addr, active: AtomicUsize::new(0), healthy: AtomicBool::new(true) }))
+            .collect();
+
+        let mut ring = Vec::new();
+        if balance == Balance::ConsistentHash {
+            for (index, backend) in backends.iter().enumerate() {
+                for node in 0..VIRTUAL_NODES {
+                    ring.push((hash(format!("{}#{}", backend.addr, node).as_bytes()), index));
+                }
+            }
+            ring.sort_unstable();
+        }
+
+        Self { backends, balance, next: AtomicUsize::new(0), ring }
+    }
+
+    pub fn len(&self) -> usize {
+        self.backends.len()
+    }
+
+    pub fn addrs(&self) -> Vec<&str> {
+        self.backends.iter().map(|backend| backend.addr.as_str()).collect()
+    }
+
+    // 按均衡策略选择在线的后端，client 是一致性哈希使用的客户端标识；全部下线时返回错误
+    pub fn pick(&self, client: &str) -> io::Result<BackendGuard> {
+        let count = self.backends.len();
+        let healthy = |index: &usize| self.backends[*index].is_healthy();
+        let index = match self.balance {
+            Balance::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..count).map(|offset| (start + offset) % count).find(healthy)
+            }
+            Balance::Random => {
+                let candidates: Vec<usize> = (0..count).filter(healthy).collect();
+                (!candidates.is_empty()).then(|| candidates[OsRng.next_u32() as usize % candidates.len()])
+            }
+            // 连接数相同时轮流选择，避免总是落在第一个后端
+            Balance::LeastConnections => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..count)
+                    .map(|offset| (start + offset) % count)
+                    .filter(healthy)
+                    .min_by_key(|index| self.backends[*index].active.load(Ordering::Relaxed))
+            }
+            // 沿哈希环找到第一个在线的后端，下线后端的客户端分散到环上的下一个后端
+            Balance::ConsistentHash => {
+                let point = hash(client.as_bytes());
+                let position = self.ring.partition_point(|(node, _)| *node < point);
+                (0..self.ring.len())
+                    .map(|offset| self.ring[(position + offset) % self.ring.len()].1)
+                    .find(healthy)
+            }
+        };
+
+        let Some(index) = index else {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "All backends are down"));
+        };
+        let backend = self.backends[index].clone();
+        backend.active.fetch_add(1, Ordering::Relaxed);
+        Ok(BackendGuard { backend })
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheck {
+    // 检查间隔和单次检查超时，单位秒
+    #[serde(default = "default_interval")]
+    pub interval: f64,
+    #[serde(default = "default_timeout")]
+    pub timeout: f64,
+    // 连续成功 rise 次判定上线，连续失败 fall 次判定下线
+    #[serde(default = "default_rise")]
+    pub rise: u32,
+    #[serde(default = "default_fall")]
+    pub fall: u32,
+    // 连接后发送的内容，以及响应中必须包含的内容
+    pub send: Option<String>,
+    pub expect: Option<String>,
+}
+
+fn default_interval() -> f64 {
+    5.0
+}
+
+fn default_timeout() -> f64 {
+    2.0
+}
+
+fn default_rise() -> u32 {
+    2
+}
+
+fn default_fall() -> u32 {
+    3
+}
+
+// 响应中查找 expect 时最多读取的字节数
+const MAX_EXPECT_READ: usize = 64 * 1024;
+
+impl HealthCheck {
+    pub fn validate(&self) -> Result<(), String> {
+        let positive = |seconds: f64| seconds.is_finite() && seconds > 0.0;
+        if !positive(self.interval) || !positive(self.timeout) {
+            return Err("health_check interval and timeout must be positive numbers of seconds".to_string());
+        }
+        if self.rise == 0 || self.fall == 0 {
+            return Err("health_check rise and fall must be at least 1".to_string());
+        }
+        if self.expect.as_ref().is_some_and(|expect| expect.is_empty() || expect.len() > MAX_EXPECT_READ) {
+            return Err(format!("health_check expect must be between 1 and {} bytes", MAX_EXPECT_READ));
+        }
+        Ok(())
+    }
+}
+
+// 为每个后端运行健康检查，直到收到停止信号
+pub async fn health_checks(
+    forward: Forward,
+    state: ForwardState,
+    config: HealthCheck,
+    mut stop_receiver: broadcast::Receiver<()>,
+) -> io::Result<()> {
+    let mut checks = JoinSet::new();
+    for backend in &state.upstreams.backends {
+        checks.spawn(check_backend(forward.clone(), state.clone(), backend.clone(), config.clone()));
+    }
+    let _ = stop_receiver.recv().await;
+    checks.abort_all();
+    Ok(())
+}
+
+async fn check_backend(forward: Forward, state: ForwardState, backend: Arc<Backend>, config: HealthCheck) {
+    let name = &forward.name;
+    let mut ticker = interval(Duration::from_secs_f64(config.interval));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut successes = 0u32;
+    let mut failures = 0u32;
+
+    loop {
+        ticker.tick().await;
+        let result = match timeout(Duration::from_secs_f64(config.timeout), probe(&forward, &state, &backend.addr, &config)).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("no answer within {}s", config.timeout)),
+        };
+
+        // 连续结果达到阈值才切换状态，避免偶发失败导致抖动
+        match result {
+            Ok(()) => {
+                successes += 1;
+                failures = 0;
+                if !backend.is_healthy() && successes >= config.rise {
+                    backend.healthy.store(true, Ordering::Relaxed);
+                    async_info!("[ ",name," ] Backend ",backend.addr," is up");
+                }
+            }
+            Err(reason) => {
+                failures += 1;
+                successes = 0;
+                if backend.is_healthy() && failures >= config.fall {
+                    backend.healthy.store(false, Ordering::Relaxed);
+                    async_error!("[ ",name," ] Backend ",backend.addr," is down: ",reason);
+                }
+            }
+        }
+    }
+}
+
+// 单次检查：按转发的配置建立远程链路（包括 TLS 和加密握手），并且配置了 send / expect 时得到预期的响应。
+// 没有 send / expect 时加密链路按多路复用会话握手，不打开流，对端不会为检查连接它的上游
+async fn probe(forward: &Forward, state: &ForwardState, addr: &str, config: &HealthCheck) -> Result<(), String> {
+    let exchange = config.send.is_some() || config.expect.is_some();
+    let mut link = open_remote(forward, state, addr, !exchange).await.map_err(|e| e.to_string())?;
+    let result = exchange_probe(&mut link, config).await;
+    tokio::spawn(close_probe(link, exchange, Duration::from_secs_f64(config.timeout)));
+    result
+}
+
+// 像正常连接一样结束：发送 EOF 并读完对端剩余的数据，对端不会记录连接异常断开；
+// 多路复用会话上只能直接关闭
+async fn close_probe(mut link: Link, exchange: bool, wait: Duration) {
+    if exchange && link.writer().send(Frame::Eof).await.is_ok() {
+        let _ = timeout(wait, async {
+            while let Ok(Some(frame)) = link.reader().read_frame().await {
+                if matches!(frame, Frame::Eof | Frame::Close(_)) {
+                    break;
+                }
+            }
+        })
+        .await;
+    }
+    let _ = link.writer().shutdown().await;
+}
+
+// send 和 expect 作为链路上的数据收发，TLS 和加密后端同样适用
+async fn exchange_probe(link: &mut Link, config: &HealthCheck) -> Result<(), String> {
+    if let Some(send) = &config.send {
+        link.writer().send(Frame::Data(send.as_bytes().to_vec())).await.map_err(|e| format!("send failed: {}", e))?;
+    }
+
+    let Some(expect) = &config.expect else {
+        return Ok(());
+    };
+    let expect = expect.as_bytes();
+    let mut response = Vec::new();
+    while response.len() < MAX_EXPECT_READ {
+        match link.reader().read_frame().await.map_err(|e| format!("read failed: {}", e))? {
+            Some(Frame::Data(data)) => {
+                response.extend_from_slice(&data);
+                if response.windows(expect.len()).any(|window| window == expect) {
+                    return Ok(());
+                }
+            }
+            Some(Frame::Close(reason)) => return Err(format!("peer closed the connection: {}", reason)),
+            Some(Frame::Eof) | None => break,
+            // 保活和窗口更新等控制帧不影响检查结果
+            Some(_) => {}
+        }
+    }
+    Err(format!("response did not contain {:?}", String::from_utf8_lossy(expect)))
+}
+
+fn hash(data: &[u8]) -> u64 {
+    let digest = Sha256::digest(data);
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes)
+}